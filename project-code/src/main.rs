@@ -1,15 +1,23 @@
 extern crate ctrlc;
+#[cfg(feature = "gif")]
+extern crate gif;
 extern crate libc;
 extern crate mmap;
 extern crate nix;
+#[cfg(feature = "png")]
+extern crate png;
 extern crate shuteye;
 #[macro_use]
 extern crate simple_error;
 extern crate termion;
 extern crate time;
 
-use std::io::stdout;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::{Read, stdout};
 use std::io::Write;
+use std::os::unix::fs::FileTypeExt;
 use std::path::Path;
 use std::process::exit;
 use std::sync::{Arc, mpsc};
@@ -17,98 +25,326 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time::Duration;
 
+use nix::sys::signal::{SigSet, Signal};
 use termion::input::TermRead;
 use termion::raw::IntoRawMode;
 
 use snake_game::game::*;
 use snake_game::snake::*;
-use utils::file_reader;
+use options::Options;
+use utils::animation::Animation;
+use utils::backend;
+use utils::backend::{Backend, BackendKind, GpioMatrix};
 use utils::frame::Frame;
-use utils::gpio_driver::GPIO;
+use utils::gamma::{BrightnessControl, ColorLut};
+use utils::gpio_driver::{DriverError, GpioSlowdown, GPIO, MAX_PWM_BITS};
+use utils::gpio_trace::TracingRegisterBlock;
+use utils::image_source;
+use utils::matrix::Matrix;
+use utils::pi_model;
+use utils::realtime::RealtimeSettings;
+use utils::recorder::RecordingMatrix;
+use utils::register_block::{MmapRegisterBlock, RegisterBlock};
+use utils::resample;
+use utils::terminal_matrix::TerminalMatrix;
 use utils::time::Timer;
 
+mod options;
 mod utils;
 mod snake_game;
 
+const BRIGHTNESS_STEP: u8 = 10;
+
 pub fn main() {
     let args: Vec<String> = std::env::args().collect();
-    sanity_check(&args);
+    let options = match options::parse_options(&args) {
+        Ok(options) => options,
+        Err(why) => {
+            eprintln!("{}\n{}", why, options::USAGE);
+            std::process::exit(1);
+        }
+    };
 
     let interrupt_received = Arc::new(AtomicBool::new(false));
-    let mut gpio = GPIO::new(1);
-    let timer = Timer::new();
-    let mut frame = Frame::new();
+    let mut backend: Box<dyn Backend + Send> = match options.backend {
+        BackendKind::Gpio => gpio_backend(&options),
+        BackendKind::Terminal => match TerminalMatrix::new(stdout()) {
+            Ok(terminal) => Box::new(terminal),
+            Err(why) => {
+                eprintln!("Could not write to the terminal: {}", why);
+                std::process::exit(1);
+            }
+        },
+        BackendKind::Record => match RecordingMatrix::create(options.record_format, Path::new(&options.record_path)) {
+            Ok(recorder) => Box::new(recorder),
+            Err(why) => {
+                eprintln!("Could not record to {}: {}", options.record_path, why);
+                std::process::exit(1);
+            }
+        },
+    };
+    let mut frame = new_frame(&options);
 
-    if &args[1] == "snake" {
+    if options.command == "snake" {
         println!("Starting Snake");
-        let mut game = Game::new();
+        let mut game = Game::new(frame.width, frame.height);
         let int_recv = interrupt_received.clone();
         let (tx, rx) = mpsc::channel::<Option<Direction>>();
         let mut prev_frame_time = time::get_time();
 
         thread::spawn(move || loop {
-            let buffer: Option < Direction >;
-            buffer = wait_for_key_press();
+            let buffer: Option<Direction> = wait_for_key_press();
             tx.send(buffer).unwrap();
-            println!("send!");
         });
 
         ctrlc::set_handler(move || {
             int_recv.store(true, Ordering::SeqCst);
         }).unwrap();
 
+        // The refresh thread shows one frame while the game draws in the other.
+        let matrix = match Matrix::start(backend, new_frame(&options), options.realtime) {
+            Ok(matrix) => matrix,
+            Err(why) => {
+                eprintln!("{}", why);
+                std::process::exit(1);
+            }
+        };
         game.draw(&mut frame);
-        loop{
+        frame = matrix.swap_on_vsync(frame);
+        while interrupt_received.load(Ordering::SeqCst) == false {
             if !game.is_game_over(){
                 let option = match rx.try_recv() {
                     Ok(dir) => dir,
                     Err(_err) => None
                 };
 
-                if option.is_some() {
-                    println!("option: {:?}", option);
-                }
-
                 game.key_pressed(option);
                 if game.update(&mut prev_frame_time) {
                     prev_frame_time = time::get_time();
                     game.draw(&mut frame);
+                    frame = matrix.swap_on_vsync(frame);
                 }
             }
-            gpio.render_frame(&mut frame, &timer);
+            thread::sleep(Duration::from_millis(1));
         }
+        matrix.stop();
+        println!("Received CTRL-C");
+    }
+    //RENDER VIDEO
+    else if is_stream(&options.command) {
+        println!("Rendering Video");
+        let input: Box<dyn Read + Send> = if options.command == "-" {
+            Box::new(io::stdin())
+        } else {
+            match File::open(&options.command) {
+                Ok(file) => Box::new(file),
+                Err(why) => {
+                    eprintln!("Could not open {}: {}", options.command, why);
+                    std::process::exit(1);
+                }
+            }
+        };
+        let mut source = match image_source::image_source_from_reader(input) {
+            Ok(source) => source,
+            Err(why) => {
+                eprintln!("Could not read {}: {}", options.command, why);
+                std::process::exit(1);
+            }
+        };
+        let (tx, rx) = mpsc::sync_channel(1);
+        let (fit, filter) = (options.fit, options.filter);
+        let (width, height) = (frame.width, frame.height);
+
+        thread::spawn(move || loop {
+            match source.next_image() {
+                Ok(Some(image)) => {
+                    let image = match fit {
+                        Some(policy) => resample::fit_image(&image, width, height, policy, filter),
+                        None => image,
+                    };
+                    if tx.send(image).is_err() {
+                        break;
+                    }
+                },
+                Ok(None) => break,
+                Err(why) => {
+                    eprintln!("Could not decode the video stream: {}", why);
+                    break;
+                }
+            }
+        });
+
+        let int_recv = interrupt_received.clone();
+        ctrlc::set_handler(move || {
+            int_recv.store(true, Ordering::SeqCst);
+        }).unwrap();
+
+        // Only now, so the decoder thread does not inherit the priority.
+        apply_realtime(&options.realtime);
+        backend::render_image_stream(&mut backend, interrupt_received, rx, &mut frame);
     }
     //RENDER IMAGE
     else {
         println!("Rendering Image");
-        let path = Path::new(&args[1]);
-        let image = file_reader::read_ppm_file(&path);
+        let path = Path::new(&options.command);
+        let mut animation = match image_source::open_image_source(path)
+            .and_then(|mut source| Animation::from_source(&mut *source, options.looping)) {
+            Ok(animation) => animation,
+            Err(why) => {
+                eprintln!("Could not read {}: {}", path.display(), why);
+                std::process::exit(1);
+            }
+        };
+        if let Some(policy) = options.fit {
+            for animation_frame in animation.frames.iter_mut() {
+                animation_frame.image = resample::fit_image(&animation_frame.image, frame.width, frame.height, policy, options.filter);
+            }
+        }
         let int_recv = interrupt_received.clone();
 
         ctrlc::set_handler(move || {
             int_recv.store(true, Ordering::SeqCst);
         }).unwrap();
 
-        gpio.render_image_frame(interrupt_received, &image, &mut frame, &timer, true);
+        apply_realtime(&options.realtime);
+        if animation.frames.len() > 1 {
+            backend::render_animation(&mut backend, interrupt_received, &animation, &mut frame);
+        } else {
+            match animation.frames.pop() {
+                Some(still) => backend::render_image_frame(&mut backend, interrupt_received, &still.image, &mut frame, options.fit.is_none()),
+                None => {
+                    eprintln!("{} does not contain an image", path.display());
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+}
+
+fn gpio_backend(options: &Options) -> Box<dyn Backend + Send> {
+    let peripheral_base = options.peripheral_base.unwrap_or_else(|| {
+        match pi_model::detect_peripheral_base() {
+            Some(base) => base,
+            None => {
+                eprintln!("Could not detect the Raspberry Pi model, assuming a Pi 3 (pass --peripheral-base to override)");
+                pi_model::BCM2709_PERI_BASE
+            }
+        }
+    });
+    let slowdown = match options.gpio_slowdown {
+        GpioSlowdown::Fixed(slowdown) => slowdown,
+        GpioSlowdown::Auto => 0,
+    };
+
+    let trace_path = match &options.trace_path {
+        Some(path) => path,
+        None => {
+            let gpio = GPIO::new(slowdown, &options.panel, &options.pin_mapping, peripheral_base);
+            return Box::new(gpio_matrix(options, gpio, peripheral_base));
+        }
+    };
+    let registers = match MmapRegisterBlock::gpio(peripheral_base) {
+        Ok(registers) => registers,
+        Err(why) => {
+            eprintln!("Could not initialize the GPIO driver: {}", why);
+            std::process::exit(1);
+        }
+    };
+    let traced = File::create(trace_path)
+        .and_then(|file| TracingRegisterBlock::new(registers, BufWriter::new(file), &options.pin_mapping, options.panel.parallel));
+    match traced {
+        Ok(traced) => {
+            let gpio = GPIO::with_registers(traced, slowdown, &options.panel, &options.pin_mapping);
+            Box::new(gpio_matrix(options, gpio, peripheral_base))
+        }
+        Err(why) => {
+            eprintln!("Could not write the GPIO trace to {}: {}", trace_path, why);
+            std::process::exit(1);
+        }
     }
 }
 
-fn sanity_check(args: &Vec<String>) {
-    if nix::unistd::Uid::current().is_root() == false {
-        eprintln!("Must run as root to be able to access /dev/mem\nPrepend \'sudo\' to the command");
+fn gpio_matrix<R: RegisterBlock>(options: &Options, gpio: Result<GPIO<R>, DriverError>, peripheral_base: u64) -> GpioMatrix<R> {
+    let mut gpio = match gpio {
+        Ok(gpio) => gpio,
+        Err(why) => {
+            eprintln!("Could not initialize the GPIO driver: {}", why);
+            std::process::exit(1);
+        }
+    };
+    let mut timer = match Timer::new(peripheral_base) {
+        Ok(timer) => timer,
+        Err(why) => {
+            eprintln!("Could not initialize the timer: {}", why);
+            std::process::exit(1);
+        }
+    };
+    timer.calibrate_jitter(20);
+
+    gpio.set_brightness(options.brightness);
+    watch_brightness_signals(gpio.brightness_control());
+    gpio.set_color_lut(ColorLut::new(options.color_curves, MAX_PWM_BITS));
+    if let Err(why) = gpio.set_pwm_bits(options.pwm_bits).and_then(|_| gpio.set_pwm_lsb_nanos(options.pwm_lsb_nanos)) {
+        eprintln!("{}", why);
         std::process::exit(1);
-    } else if args.len() < 2 {
-        eprintln!("Syntax: {:?} [image]", args[0]);
+    }
+    if options.gpio_slowdown == GpioSlowdown::Auto {
+        let (slowdown, clock_hz) = gpio.calibrate_slowdown(&timer, options.max_clock_hz);
+        println!("Using GPIO slowdown {} for a {:.1} MHz panel clock", slowdown, clock_hz as f64 / 1e6);
+    }
+    GpioMatrix::new(gpio, timer)
+}
+
+// SIGUSR1 brightens the panel and SIGUSR2 dims it. This has to run before any
+// other thread is started, so that none of them takes the signals.
+fn watch_brightness_signals(control: BrightnessControl) {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGUSR1);
+    signals.add(Signal::SIGUSR2);
+    if let Err(why) = signals.thread_block() {
+        eprintln!("Could not listen for brightness signals: {}", why);
+        return;
+    }
+    thread::spawn(move || loop {
+        match signals.wait() {
+            Ok(Signal::SIGUSR1) => control.set(control.get().saturating_add(BRIGHTNESS_STEP)),
+            Ok(Signal::SIGUSR2) => control.set(control.get().saturating_sub(BRIGHTNESS_STEP).max(1)),
+            _ => {}
+        }
+    });
+}
+
+fn new_frame(options: &Options) -> Frame {
+    match Frame::with_mapper(&options.panel, &*options.pixel_mapper) {
+        Ok(frame) => frame,
+        Err(why) => {
+            eprintln!("{}", why);
+            std::process::exit(1);
+        }
+    }
+}
+
+// The image and video loops refresh the panel from the main thread.
+fn apply_realtime(realtime: &RealtimeSettings) {
+    if let Err(why) = realtime.apply_to_current_thread() {
+        eprintln!("{}", why);
         std::process::exit(1);
     }
 }
 
+// Standard input ("-") and named pipes are played as a stream of images.
+fn is_stream(command: &str) -> bool {
+    command == "-" || match std::fs::metadata(command) {
+        Ok(metadata) => metadata.file_type().is_fifo(),
+        Err(_err) => false,
+    }
+}
+
 fn wait_for_key_press() -> Option<Direction> {
     let mut stdout = stdout().into_raw_mode().unwrap();
     let mut stdin = termion::async_stdin().keys();
     let dir: Option<Direction>;
 
-    println!("init loop");
 
     loop{
         let input = stdin.next();
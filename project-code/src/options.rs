@@ -0,0 +1,391 @@
+use std::str::FromStr;
+
+use super::utils::animation::Looping;
+use super::utils::backend::BackendKind;
+use super::utils::gamma::ColorCurve;
+use super::utils::gpio_driver::{GpioSlowdown, MAX_SLOWDOWN};
+use super::utils::panel_config::PanelConfig;
+use super::utils::pin_mapping::PinMapping;
+use super::utils::pixel_mapper::{pixel_mapper_by_name, IdentityMapper, PixelMapper};
+use super::utils::realtime::RealtimeSettings;
+use super::utils::recorder::RecordFormat;
+use super::utils::resample::{Filter, FitPolicy};
+
+pub const USAGE: &str = "Syntax: project-code [options] <snake|image|->
+
+Images can be Netpbm, PNG, GIF or BMP files. Pass - or a named pipe to play a
+stream of concatenated PPM images, e.g.
+  ffmpeg -re -i video.mp4 -s 32x16 -f image2pipe -vcodec ppm - | project-code -
+
+Options:
+  --backend=<gpio|terminal|record>
+                       Drive the panel, simulate it in a truecolor terminal or write
+                       every frame to --record-path (default gpio)
+  --record-format=<ppm|gif|raw>
+                       A directory of numbered PPM files, an animated GIF, or raw RGB
+                       frames each preceded by a little-endian u64 of microseconds (default ppm)
+  --record-path=<path> Where --backend=record writes to (default recording)
+  --led-cols=<n>       Columns of a single panel (default 32)
+  --led-rows=<n>       Rows of a single panel (default 16)
+  --led-scan-rate=<n>  Rows addressed per sub panel (default rows / 2)
+  --led-chain=<n>      Number of daisy-chained panels (default 1)
+  --led-parallel=<n>   Number of parallel chains (default 1)
+  --led-pixel-mapper=<identity|upside-down|snake:<rows>>
+                       How the chained panels are arranged (default identity)
+  --led-gpio-mapping=<adafruit-hat|adafruit-hat-pwm|regular|classic>
+                       How the panels are wired to the GPIO pins (default adafruit-hat)
+  --led-gamma=<cie1931|<gamma>>[,<green>,<blue>]
+                       Brightness curve, for all channels or per channel (default cie1931)
+  --led-pwm-bits=<1-11>
+                       Number of bit planes shown per row (default 8)
+  --led-pwm-lsb-nanoseconds=<n>
+                       On-time of the least significant plane (default 1000)
+  --led-brightness=<percent>
+                       Brightness of the panel, 1-100 (default 100). While running,
+                       SIGUSR1 and SIGUSR2 raise and lower it in steps of 10
+  --led-slowdown-gpio=<0-4|auto>
+                       Repeat every write to the pins, for panels that cannot keep up with
+                       faster Pis, or measure the smallest one that does (default 1)
+  --led-max-clock-mhz=<n>
+                       Fastest panel clock --led-slowdown-gpio=auto allows (default 10)
+  --led-rt-priority=<1-99>
+                       Refresh with SCHED_FIFO priority (needs CAP_SYS_NICE)
+  --led-cpu=<n>        Pin the refresh to this core, e.g. one reserved with isolcpus
+  --led-lock-memory=<yes|no>
+                       Lock the process in RAM (needs CAP_IPC_LOCK, default no)
+  --led-trace=<file.vcd>
+                       Log every change of the GPIO pins to a Value Change Dump for
+                       GTKWave (gpio backend only, slows down the refresh)
+  --peripheral-base=<0x...>
+                       Physical address of the BCM peripherals (default: detected)
+  --loop=<forever|n>   How often an animation is played (default forever)
+  --fit=<fit|fill|stretch|center>
+                       Resize images to the panel (default: scroll through them unscaled)
+  --resample=<nearest|bilinear|box>
+                       Filter used by --fit (default box)";
+
+pub struct Options {
+    pub command: String,
+    pub backend: BackendKind,
+    pub record_format: RecordFormat,
+    pub record_path: String,
+    pub panel: PanelConfig,
+    pub pixel_mapper: Box<dyn PixelMapper>,
+    pub pin_mapping: PinMapping,
+    pub peripheral_base: Option<u64>,
+    pub color_curves: [ColorCurve; 3],
+    pub brightness: u8,
+    pub pwm_bits: usize,
+    pub pwm_lsb_nanos: u32,
+    pub gpio_slowdown: GpioSlowdown,
+    pub max_clock_hz: u64,
+    pub realtime: RealtimeSettings,
+    pub trace_path: Option<String>,
+    pub looping: Looping,
+    pub fit: Option<FitPolicy>,
+    pub filter: Filter,
+}
+
+pub fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut command: Option<String> = None;
+    let mut backend = BackendKind::Gpio;
+    let mut record_format = RecordFormat::Ppm;
+    let mut record_path = String::from("recording");
+    let mut cols: usize = 32;
+    let mut rows: usize = 16;
+    let mut scan_rate: Option<usize> = None;
+    let mut chain_length: usize = 1;
+    let mut parallel: usize = 1;
+    let mut pixel_mapper: Box<dyn PixelMapper> = Box::new(IdentityMapper);
+    let mut pin_mapping = PinMapping::adafruit_hat();
+    let mut peripheral_base: Option<u64> = None;
+    let mut color_curves = [ColorCurve::Cie1931; 3];
+    let mut brightness: u8 = 100;
+    let mut pwm_bits: usize = 8;
+    let mut pwm_lsb_nanos: u32 = 1000;
+    let mut gpio_slowdown = GpioSlowdown::Fixed(1);
+    let mut max_clock_hz: u64 = 10 * 1000 * 1000;
+    let mut realtime = RealtimeSettings::default();
+    let mut trace_path: Option<String> = None;
+    let mut looping = Looping::Forever;
+    let mut fit: Option<FitPolicy> = None;
+    let mut filter = Filter::Box;
+
+    for arg in args.iter().skip(1) {
+        if arg.starts_with("--") {
+            let (name, value) = split_option(arg)?;
+            match name {
+                "backend" => backend = match BackendKind::from_name(value) {
+                    Some(backend) => backend,
+                    None => return Err(format!("Unknown backend {:?}", value)),
+                },
+                "record-format" => record_format = match RecordFormat::from_name(value) {
+                    Some(format) => format,
+                    None => return Err(format!("Unknown recording format {:?}", value)),
+                },
+                "record-path" => record_path = String::from(value),
+                "led-cols" => cols = parse_number(name, value)?,
+                "led-rows" => rows = parse_number(name, value)?,
+                "led-scan-rate" => scan_rate = Some(parse_number(name, value)?),
+                "led-chain" => chain_length = parse_number(name, value)?,
+                "led-parallel" => parallel = parse_number(name, value)?,
+                "led-pixel-mapper" => pixel_mapper = match pixel_mapper_by_name(value) {
+                    Some(mapper) => mapper,
+                    None => return Err(format!("Unknown pixel mapper {:?}", value)),
+                },
+                "led-gpio-mapping" => pin_mapping = match PinMapping::by_name(value) {
+                    Some(mapping) => mapping,
+                    None => return Err(format!("Unknown GPIO mapping {:?}", value)),
+                },
+                "led-gamma" => color_curves = parse_color_curves(name, value)?,
+                "led-brightness" => brightness = match parse_number::<usize>(name, value)? {
+                    percent @ 1..=100 => percent as u8,
+                    _ => return Err(format!("Brightness must be between 1 and 100 (got {})", value)),
+                },
+                "led-pwm-bits" => pwm_bits = match parse_number::<usize>(name, value)? {
+                    bits @ 1..=11 => bits,
+                    _ => return Err(format!("PWM bits must be between 1 and 11 (got {})", value)),
+                },
+                "led-pwm-lsb-nanoseconds" => pwm_lsb_nanos = parse_number(name, value)?,
+                "led-slowdown-gpio" => gpio_slowdown = match value {
+                    "auto" => GpioSlowdown::Auto,
+                    _ => match parse_number::<u32>(name, value)? {
+                        slowdown if slowdown <= MAX_SLOWDOWN => GpioSlowdown::Fixed(slowdown),
+                        _ => return Err(format!("GPIO slowdown must be between 0 and {} or auto (got {})", MAX_SLOWDOWN, value)),
+                    },
+                },
+                "led-max-clock-mhz" => max_clock_hz = match parse_number::<u64>(name, value)? {
+                    0 => return Err(String::from("The maximum clock frequency must be at least 1 MHz")),
+                    mhz => match mhz.checked_mul(1000 * 1000) {
+                        Some(hz) => hz,
+                        None => return Err(format!("Maximum clock frequency {} MHz is out of range", value)),
+                    },
+                },
+                "led-rt-priority" => realtime.priority = match parse_number::<usize>(name, value)? {
+                    priority @ 1..=99 => Some(priority as i32),
+                    _ => return Err(format!("Real-time priority must be between 1 and 99 (got {})", value)),
+                },
+                "led-cpu" => realtime.cpu = Some(parse_number(name, value)?),
+                "led-lock-memory" => realtime.lock_memory = match value {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err(format!("Invalid value {:?} for --{}, expected yes or no", value, name)),
+                },
+                "led-trace" => trace_path = Some(String::from(value)),
+                "peripheral-base" => peripheral_base = Some(parse_address(name, value)?),
+                "loop" => looping = match Looping::from_name(value) {
+                    Some(looping) => looping,
+                    None => return Err(format!("Invalid value {:?} for --loop, expected forever or a count", value)),
+                },
+                "fit" => fit = match FitPolicy::from_name(value) {
+                    Some(policy) => Some(policy),
+                    None => return Err(format!("Unknown fit policy {:?}", value)),
+                },
+                "resample" => filter = match Filter::from_name(value) {
+                    Some(filter) => filter,
+                    None => return Err(format!("Unknown resample filter {:?}", value)),
+                },
+                _ => return Err(format!("Unknown option --{}", name)),
+            }
+        } else if command.is_none() {
+            command = Some(arg.clone());
+        } else {
+            return Err(format!("Unexpected argument {:?}", arg));
+        }
+    }
+
+    let command = match command {
+        Some(command) => command,
+        None => return Err(String::from("Missing snake or image argument")),
+    };
+
+    let panel = PanelConfig::new(cols, rows, scan_rate.unwrap_or(rows / 2))?.chained(chain_length, parallel)?;
+    pixel_mapper.check(&panel)?;
+    if trace_path.is_some() && backend != BackendKind::Gpio {
+        return Err(String::from("--led-trace only works with --backend=gpio"));
+    }
+    pin_mapping.validate()?;
+    pin_mapping.check_panel(&panel)?;
+
+    Ok(Options {
+        command,
+        backend,
+        record_format,
+        record_path,
+        panel,
+        pixel_mapper,
+        pin_mapping,
+        peripheral_base,
+        color_curves,
+        brightness,
+        pwm_bits,
+        pwm_lsb_nanos,
+        gpio_slowdown,
+        max_clock_hz,
+        realtime,
+        trace_path,
+        looping,
+        fit,
+        filter,
+    })
+}
+
+fn split_option(arg: &str) -> Result<(&str, &str), String> {
+    let option = &arg[2..];
+    match option.find('=') {
+        Some(index) => Ok((&option[..index], &option[index + 1..])),
+        None => Err(format!("Option {} needs a value (--name=value)", arg)),
+    }
+}
+
+fn parse_number<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.parse::<T>()
+        .map_err(|_err| format!("Invalid value {:?} for --{}", value, name))
+}
+
+fn parse_color_curves(name: &str, value: &str) -> Result<[ColorCurve; 3], String> {
+    let curves: Vec<Option<ColorCurve>> = value.split(',').map(ColorCurve::from_name).collect();
+    match curves.as_slice() {
+        [Some(curve)] => Ok([*curve; 3]),
+        [Some(red), Some(green), Some(blue)] => Ok([*red, *green, *blue]),
+        _ => Err(format!("Invalid value {:?} for --{}", value, name)),
+    }
+}
+
+fn parse_address(name: &str, value: &str) -> Result<u64, String> {
+    let digits = value.trim_start_matches("0x").trim_start_matches("0X");
+    u64::from_str_radix(digits, 16)
+        .map_err(|_err| format!("Invalid address {:?} for --{}", value, name))
+}
+
+#[cfg(test)]
+fn args(list: &[&str]) -> Vec<String> {
+    list.iter().map(|arg| String::from(*arg)).collect()
+}
+
+#[test]
+fn parse_options_test() {
+    let options = parse_options(&args(&["project-code", "--led-cols=64", "--led-rows=32", "snake"])).unwrap();
+
+    assert_eq!("snake", options.command);
+    assert_eq!(PanelConfig::new(64, 32, 16).unwrap(), options.panel);
+}
+
+#[test]
+fn parse_options_rejects_unknown_option_test() {
+    assert!(parse_options(&args(&["project-code", "--led-colour=red", "snake"])).is_err());
+}
+
+#[test]
+fn parse_options_rejects_unsupported_parallel_chains_test() {
+    assert!(parse_options(&args(&["project-code", "--led-parallel=2", "snake"])).is_err());
+
+    assert_eq!("regular", parse_options(&args(&["project-code", "--led-parallel=2", "--led-gpio-mapping=regular", "snake"])).unwrap().pin_mapping.name);
+}
+
+#[test]
+fn parse_options_rejects_invalid_panel_size_test() {
+    for arg in &["--led-rows=20", "--led-scan-rate=3", "--led-cols=0", "--led-rows=0"] {
+        assert!(parse_options(&args(&["project-code", *arg, "snake"])).is_err(), "{} should be rejected", arg);
+    }
+}
+
+#[test]
+fn parse_options_rejects_invalid_chains_test() {
+    assert!(parse_options(&args(&["project-code", "--led-chain=0", "snake"])).is_err());
+    assert!(parse_options(&args(&["project-code", "--led-parallel=0", "snake"])).is_err());
+    assert!(parse_options(&args(&["project-code", "--led-pixel-mapper=snake:2", "snake"])).is_err());
+    assert!(parse_options(&args(&["project-code", "--led-chain=4", "--led-pixel-mapper=snake:2", "snake"])).is_ok());
+}
+
+#[test]
+fn parse_options_peripheral_base_test() {
+    assert_eq!(Some(0xFE000000), parse_options(&args(&["project-code", "--peripheral-base=0xFE000000", "snake"])).unwrap().peripheral_base);
+}
+
+#[test]
+fn parse_options_loop_test() {
+    assert_eq!(Looping::Count(3), parse_options(&args(&["project-code", "--loop=3", "animation.gif"])).unwrap().looping);
+
+    assert!(parse_options(&args(&["project-code", "--loop=never", "animation.gif"])).is_err());
+}
+
+#[test]
+fn parse_options_fit_test() {
+    let options = parse_options(&args(&["project-code", "--fit=fill", "--resample=bilinear", "image.png"])).unwrap();
+    assert_eq!(Some(FitPolicy::Fill), options.fit);
+    assert_eq!(Filter::Bilinear, options.filter);
+}
+
+#[test]
+fn parse_options_gamma_test() {
+    assert_eq!([ColorCurve::Gamma(2.2), ColorCurve::Gamma(2.0), ColorCurve::Gamma(2.4)], parse_options(&args(&["project-code", "--led-gamma=2.2,2.0,2.4", "snake"])).unwrap().color_curves);
+
+    assert!(parse_options(&args(&["project-code", "--led-gamma=2.2,2.0", "snake"])).is_err());
+}
+
+#[test]
+fn parse_options_brightness_test() {
+    assert_eq!(30, parse_options(&args(&["project-code", "--led-brightness=30", "snake"])).unwrap().brightness);
+
+    assert!(parse_options(&args(&["project-code", "--led-brightness=0", "snake"])).is_err());
+}
+
+#[test]
+fn parse_options_pwm_test() {
+    let options = parse_options(&args(&["project-code", "--led-pwm-bits=11", "--led-pwm-lsb-nanoseconds=130", "snake"])).unwrap();
+    assert_eq!((11, 130), (options.pwm_bits, options.pwm_lsb_nanos));
+
+    assert!(parse_options(&args(&["project-code", "--led-pwm-bits=12", "snake"])).is_err());
+    assert!(parse_options(&args(&["project-code", "--led-pwm-lsb-nanoseconds=4294967296", "snake"])).is_err());
+}
+
+#[test]
+fn parse_options_realtime_test() {
+    let options = parse_options(&args(&["project-code", "--led-rt-priority=50", "--led-cpu=3", "--led-lock-memory=yes", "snake"])).unwrap();
+    assert_eq!(RealtimeSettings { priority: Some(50), cpu: Some(3), lock_memory: true }, options.realtime);
+
+    assert_eq!(RealtimeSettings::default(), parse_options(&args(&["project-code", "snake"])).unwrap().realtime);
+
+    assert!(parse_options(&args(&["project-code", "--led-rt-priority=100", "snake"])).is_err());
+}
+
+#[test]
+fn parse_options_backend_test() {
+    assert_eq!(BackendKind::Terminal, parse_options(&args(&["project-code", "--backend=terminal", "snake"])).unwrap().backend);
+
+    assert_eq!(BackendKind::Gpio, parse_options(&args(&["project-code", "snake"])).unwrap().backend);
+
+    assert!(parse_options(&args(&["project-code", "--backend=hdmi", "snake"])).is_err());
+}
+
+#[test]
+fn parse_options_record_test() {
+    let options = parse_options(&args(&["project-code", "--backend=record", "--record-format=gif", "--record-path=out.gif", "snake"])).unwrap();
+    assert_eq!((BackendKind::Record, RecordFormat::Gif), (options.backend, options.record_format));
+    assert_eq!("out.gif", options.record_path);
+
+    assert!(parse_options(&args(&["project-code", "--record-format=avi", "snake"])).is_err());
+}
+
+#[test]
+fn parse_options_trace_test() {
+    assert_eq!(Some(String::from("refresh.vcd")), parse_options(&args(&["project-code", "--led-trace=refresh.vcd", "snake"])).unwrap().trace_path);
+
+    assert!(parse_options(&args(&["project-code", "--backend=terminal", "--led-trace=refresh.vcd", "snake"])).is_err());
+}
+
+#[test]
+fn parse_options_slowdown_test() {
+    assert_eq!(GpioSlowdown::Fixed(3), parse_options(&args(&["project-code", "--led-slowdown-gpio=3", "snake"])).unwrap().gpio_slowdown);
+
+    let options = parse_options(&args(&["project-code", "--led-slowdown-gpio=auto", "--led-max-clock-mhz=20", "snake"])).unwrap();
+    assert_eq!((GpioSlowdown::Auto, 20 * 1000 * 1000), (options.gpio_slowdown, options.max_clock_hz));
+
+    assert_eq!(GpioSlowdown::Fixed(1), parse_options(&args(&["project-code", "snake"])).unwrap().gpio_slowdown);
+
+    for arg in &["--led-slowdown-gpio=5", "--led-slowdown-gpio=fast", "--led-slowdown-gpio=4294967296",
+                 "--led-max-clock-mhz=0", "--led-max-clock-mhz=18446744073709552"] {
+        assert!(parse_options(&args(&["project-code", *arg, "snake"])).is_err(), "{} should be rejected", arg);
+    }
+}
@@ -5,7 +5,6 @@ use time::Timespec;
 
 use super::snake::{Direction, Snake};
 use super::super::utils::frame::Frame;
-use super::super::utils::gpio_driver::{COLUMNS, ROWS};
 use super::super::utils::pixel::Pixel;
 
 use self::rand::Rng;
@@ -26,15 +25,15 @@ pub struct Game {
 }
 
 impl Game {
-    pub fn new() -> Game {
+    pub fn new(width: usize, height: usize) -> Game {
         Game {
             snake: Snake::new(2, 2),
             food_exist: true,
             food_x: 5,
             food_y: 3,
             FOOD_BLOCK: Pixel::new_colored_pixel(255, 0, 0),
-            width: COLUMNS as i32,
-            height: ROWS as i32,
+            width: width as i32,
+            height: height as i32,
             is_game_over: false,
         }
     }
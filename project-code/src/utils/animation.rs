@@ -0,0 +1,258 @@
+use std::time::{Duration, Instant};
+
+use super::image::Image;
+use super::image_source::{ImageSource, SourceError};
+use super::pixel::Pixel;
+
+// Browsers show GIF frames with a delay below 20ms for 100ms, so most
+// animations are made with that in mind.
+const MIN_FRAME_DELAY: Duration = Duration::from_millis(20);
+const DEFAULT_FRAME_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Looping {
+    Count(u32),
+    Forever,
+}
+
+impl Looping {
+    pub fn from_name(name: &str) -> Option<Looping> {
+        match name {
+            "forever" => Some(Looping::Forever),
+            _ => match name.parse::<u32>() {
+                Ok(count) if count > 0 => Some(Looping::Count(count)),
+                _ => None,
+            },
+        }
+    }
+}
+
+pub struct AnimationFrame {
+    pub image: Image,
+    pub delay: Duration,
+}
+
+pub struct Animation {
+    pub frames: Vec<AnimationFrame>,
+    pub looping: Looping,
+}
+
+impl Animation {
+    /// Reads every image of `source`. Sources without timing information,
+    /// such as still images, get the default delay.
+    pub fn from_source(source: &mut dyn ImageSource, looping: Looping) -> Result<Animation, SourceError> {
+        let mut frames: Vec<AnimationFrame> = Vec::new();
+        while let Some(image) = source.next_image()? {
+            let delay = match source.frame_delay() {
+                Some(delay) if delay >= MIN_FRAME_DELAY => delay,
+                _ => DEFAULT_FRAME_DELAY,
+            };
+            frames.push(AnimationFrame { image, delay });
+        }
+        Ok(Animation { frames, looping })
+    }
+}
+
+/// What happens to the area of a frame before the next one is drawn.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Disposal {
+    Keep,
+    Background,
+    Previous,
+}
+
+/// A rectangle of new pixels to draw on the canvas. `None` is transparent.
+pub struct FramePatch {
+    pub left: usize,
+    pub top: usize,
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Option<Pixel>>,
+    pub disposal: Disposal,
+}
+
+/// Builds the full images of an animation out of its frame patches.
+pub struct Compositor {
+    canvas: Image,
+    saved_canvas: Option<Image>,
+    pending: Option<(Disposal, usize, usize, usize, usize)>,
+}
+
+impl Compositor {
+    pub fn new(width: usize, height: usize) -> Compositor {
+        Compositor {
+            canvas: Image {
+                width: width as u32,
+                height: height as u32,
+                pixels: vec![vec![Pixel::new(); width]; height],
+            },
+            saved_canvas: None,
+            pending: None,
+        }
+    }
+
+    pub fn draw(&mut self, patch: &FramePatch) -> Image {
+        self.dispose_previous();
+
+        if patch.disposal == Disposal::Previous {
+            self.saved_canvas = Some(self.canvas.clone());
+        }
+        for (index, pixel) in patch.pixels.iter().enumerate() {
+            let (row, col) = (patch.top + index / patch.width, patch.left + index % patch.width);
+            if let Some(pixel) = pixel {
+                if row < self.canvas.pixels.len() && col < self.canvas.pixels[row].len() {
+                    self.canvas.pixels[row][col] = *pixel;
+                }
+            }
+        }
+        self.pending = Some((patch.disposal, patch.left, patch.top, patch.width, patch.height));
+
+        self.canvas.clone()
+    }
+
+    // The background of a panel is black, unlit LEDs.
+    fn dispose_previous(&mut self) {
+        match self.pending.take() {
+            Some((Disposal::Background, left, top, width, height)) => {
+                for row in self.canvas.pixels.iter_mut().skip(top).take(height) {
+                    for pixel in row.iter_mut().skip(left).take(width) {
+                        *pixel = Pixel::new();
+                    }
+                }
+            }
+            Some((Disposal::Previous, _, _, _, _)) => {
+                if let Some(saved_canvas) = self.saved_canvas.take() {
+                    self.canvas = saved_canvas;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Decides which frame of an animation is on screen at a given moment.
+pub struct AnimationPlayer<'a> {
+    animation: &'a Animation,
+    index: usize,
+    loops_played: u32,
+    frame_started: Instant,
+    finished: bool,
+}
+
+impl<'a> AnimationPlayer<'a> {
+    pub fn new(animation: &'a Animation, now: Instant) -> AnimationPlayer<'a> {
+        AnimationPlayer {
+            animation,
+            index: 0,
+            loops_played: 0,
+            frame_started: now,
+            finished: animation.frames.is_empty(),
+        }
+    }
+
+    pub fn current_image(&self) -> Option<&'a Image> {
+        self.animation.frames.get(self.index).map(|frame| &frame.image)
+    }
+
+    /// Returns the image to show if it changed since the last call. Frames
+    /// that should already have ended are skipped.
+    pub fn update(&mut self, now: Instant) -> Option<&'a Image> {
+        let mut changed = false;
+        while !self.finished && now.duration_since(self.frame_started) >= self.animation.frames[self.index].delay {
+            self.frame_started += self.animation.frames[self.index].delay;
+            if self.index + 1 < self.animation.frames.len() {
+                self.index += 1;
+                changed = true;
+            } else {
+                self.loops_played += 1;
+                match self.animation.looping {
+                    Looping::Count(count) if self.loops_played >= count => self.finished = true,
+                    _ => {
+                        changed = self.index != 0;
+                        self.index = 0;
+                    }
+                }
+            }
+        }
+        if changed { self.current_image() } else { None }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+#[cfg(test)]
+fn single_pixel_image(r: u16) -> Image {
+    Image {
+        width: 1,
+        height: 1,
+        pixels: vec![vec![Pixel::new_colored_pixel(r, 0, 0)]],
+    }
+}
+
+#[cfg(test)]
+fn test_animation(looping: Looping) -> Animation {
+    Animation {
+        frames: vec![
+            AnimationFrame { image: single_pixel_image(1), delay: Duration::from_millis(100) },
+            AnimationFrame { image: single_pixel_image(2), delay: Duration::from_millis(50) },
+        ],
+        looping,
+    }
+}
+
+#[test]
+fn animation_player_follows_delays_test() {
+    let animation = test_animation(Looping::Forever);
+    let start = Instant::now();
+    let mut player = AnimationPlayer::new(&animation, start);
+
+    assert_eq!(1, player.current_image().unwrap().pixels[0][0].r);
+    assert!(player.update(start + Duration::from_millis(99)).is_none());
+    assert_eq!(2, player.update(start + Duration::from_millis(100)).unwrap().pixels[0][0].r);
+    assert_eq!(1, player.update(start + Duration::from_millis(150)).unwrap().pixels[0][0].r);
+    // A late update skips the frames it missed.
+    assert_eq!(2, player.update(start + Duration::from_millis(410)).unwrap().pixels[0][0].r);
+    assert!(!player.is_finished());
+}
+
+#[test]
+fn animation_player_loop_count_test() {
+    let animation = test_animation(Looping::Count(2));
+    let start = Instant::now();
+    let mut player = AnimationPlayer::new(&animation, start);
+
+    player.update(start + Duration::from_millis(299));
+    assert!(!player.is_finished());
+    player.update(start + Duration::from_millis(300));
+    assert!(player.is_finished());
+    assert_eq!(2, player.current_image().unwrap().pixels[0][0].r);
+}
+
+#[test]
+fn compositor_disposal_test() {
+    let red = Pixel::new_colored_pixel(255, 0, 0);
+    let blue = Pixel::new_colored_pixel(0, 0, 255);
+    let mut compositor = Compositor::new(2, 1);
+
+    let background = FramePatch { left: 0, top: 0, width: 2, height: 1, pixels: vec![Some(red), Some(red)], disposal: Disposal::Keep };
+    assert_eq!(vec![red, red], compositor.draw(&background).pixels[0]);
+
+    let restored = FramePatch { left: 1, top: 0, width: 1, height: 1, pixels: vec![Some(blue)], disposal: Disposal::Previous };
+    assert_eq!(vec![red, blue], compositor.draw(&restored).pixels[0]);
+
+    let cleared = FramePatch { left: 0, top: 0, width: 2, height: 1, pixels: vec![None, Some(blue)], disposal: Disposal::Background };
+    assert_eq!(vec![red, blue], compositor.draw(&cleared).pixels[0]);
+
+    let transparent = FramePatch { left: 0, top: 0, width: 1, height: 1, pixels: vec![None], disposal: Disposal::Keep };
+    assert_eq!(vec![Pixel::new(), Pixel::new()], compositor.draw(&transparent).pixels[0]);
+}
+
+#[test]
+fn looping_from_name_test() {
+    assert_eq!(Some(Looping::Forever), Looping::from_name("forever"));
+    assert_eq!(Some(Looping::Count(3)), Looping::from_name("3"));
+    assert_eq!(None, Looping::from_name("0"));
+    assert_eq!(None, Looping::from_name("sometimes"));
+}
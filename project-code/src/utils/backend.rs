@@ -0,0 +1,194 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time::Instant;
+
+use time;
+use time::Timespec;
+
+use super::animation::{Animation, AnimationPlayer};
+use super::frame::Frame;
+use super::gpio_driver::GPIO;
+use super::image::Image;
+use super::register_block::{MmapRegisterBlock, RegisterBlock};
+use super::time::{Clock, Timer};
+#[cfg(test)]
+use super::gpio_driver::GPSET0;
+#[cfg(test)]
+use super::pixel::Pixel;
+#[cfg(test)]
+use super::pixel_mapper::IdentityMapper;
+#[cfg(test)]
+use super::register_block::{FakePanel, RegisterWrite};
+
+/// Something that shows frames: the LED panel, or a stand-in for it.
+pub trait Backend {
+    /// Called after every change to `frame`.
+    fn load_frame(&mut self, frame: &Frame);
+
+    /// Shows the loaded frame once. The render loops call this as often as
+    /// the backend lets them, with the frame that was loaded last.
+    fn refresh(&mut self, frame: &Frame);
+
+    /// Turns the display off when rendering stops.
+    fn blank(&mut self);
+}
+
+impl<B: Backend + ?Sized> Backend for Box<B> {
+    fn load_frame(&mut self, frame: &Frame) {
+        (**self).load_frame(frame)
+    }
+
+    fn refresh(&mut self, frame: &Frame) {
+        (**self).refresh(frame)
+    }
+
+    fn blank(&mut self) {
+        (**self).blank()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BackendKind {
+    Gpio,
+    Terminal,
+    Record,
+}
+
+impl BackendKind {
+    pub fn from_name(name: &str) -> Option<BackendKind> {
+        match name {
+            "gpio" => Some(BackendKind::Gpio),
+            "terminal" => Some(BackendKind::Terminal),
+            "record" => Some(BackendKind::Record),
+            _ => None,
+        }
+    }
+}
+
+/// The panel on the GPIO pins.
+pub struct GpioMatrix<R: RegisterBlock = MmapRegisterBlock, C: Clock = Box<dyn Clock + Send>> {
+    pub gpio: GPIO<R>,
+    pub timer: Timer<C>,
+}
+
+impl<R: RegisterBlock, C: Clock> GpioMatrix<R, C> {
+    pub fn new(gpio: GPIO<R>, timer: Timer<C>) -> GpioMatrix<R, C> {
+        GpioMatrix {
+            gpio,
+            timer,
+        }
+    }
+}
+
+impl<R: RegisterBlock, C: Clock> Backend for GpioMatrix<R, C> {
+    fn load_frame(&mut self, frame: &Frame) {
+        self.gpio.load_frame(frame);
+    }
+
+    fn refresh(&mut self, frame: &Frame) {
+        self.gpio.refresh_loaded(frame, &self.timer);
+    }
+
+    fn blank(&mut self) {
+        self.gpio.disable_output();
+    }
+}
+
+pub fn render_image_frame<B: Backend>(backend: &mut B, interrupt_received: Arc<AtomicBool>, image: &Image, frame: &mut Frame, scrolling: bool) {
+    frame.next_image_frame(image);
+    backend.load_frame(frame);
+    let mut prev_frame_time = time::get_time();
+    let mut current_time: Timespec;
+
+    while !interrupt_received.load(Ordering::SeqCst) {
+        backend.refresh(frame);
+
+        current_time = time::get_time();
+        let difference = current_time - prev_frame_time;
+        if scrolling && difference >= time::Duration::milliseconds(10) {
+            frame.next_image_frame(image);
+            backend.load_frame(frame);
+            prev_frame_time = current_time;
+        };
+    };
+    if interrupt_received.load(Ordering::SeqCst) {
+        println!("Received CTRL-C");
+    } else {
+        println!("Timeout reached");
+    };
+    backend.blank();
+}
+
+// Swaps the frame contents whenever the delay of the current animation
+// frame has passed. Returns once the animation has played as often as it
+// should loop, or on CTRL-C.
+pub fn render_animation<B: Backend>(backend: &mut B, interrupt_received: Arc<AtomicBool>, animation: &Animation, frame: &mut Frame) {
+    let mut player = AnimationPlayer::new(animation, Instant::now());
+    if let Some(image) = player.current_image() {
+        frame.show_image(image);
+    }
+    backend.load_frame(frame);
+
+    while !interrupt_received.load(Ordering::SeqCst) && !player.is_finished() {
+        if let Some(image) = player.update(Instant::now()) {
+            frame.show_image(image);
+            backend.load_frame(frame);
+        }
+        backend.refresh(frame);
+    };
+    if interrupt_received.load(Ordering::SeqCst) {
+        println!("Received CTRL-C");
+    };
+    backend.blank();
+}
+
+// Keeps refreshing the panel with the most recent image received. When the
+// sender hangs up the last image stays on the panel until CTRL-C.
+pub fn render_image_stream<B: Backend>(backend: &mut B, interrupt_received: Arc<AtomicBool>, images: Receiver<Image>, frame: &mut Frame) {
+    let mut stream_open = true;
+    backend.load_frame(frame);
+
+    while !interrupt_received.load(Ordering::SeqCst) {
+        let mut changed = false;
+        while stream_open {
+            match images.try_recv() {
+                Ok(image) => {
+                    frame.show_image(&image);
+                    changed = true;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => stream_open = false,
+            }
+        }
+        if changed {
+            backend.load_frame(frame);
+        }
+        backend.refresh(frame);
+    };
+    println!("Received CTRL-C");
+    backend.blank();
+}
+
+#[test]
+fn render_animation_blanks_when_finished_test() {
+    use std::time::Duration;
+
+    use super::animation::{AnimationFrame, Looping};
+
+    let panel = FakePanel::new();
+    let mut backend = panel.matrix();
+    let mut frame = Frame::with_mapper(&panel.config, &IdentityMapper).unwrap();
+    frame.pixels[0][0] = Pixel::new_colored_pixel(255, 0, 0);
+    let animation = Animation {
+        frames: vec![AnimationFrame { image: frame.to_image(), delay: Duration::from_millis(1) }],
+        looping: Looping::Count(1),
+    };
+    frame.clear_frame();
+
+    render_animation(&mut backend, Arc::new(AtomicBool::new(false)), &animation, &mut frame);
+
+    let writes = panel.registers.writes();
+    assert!(writes.contains(&RegisterWrite { word: GPSET0, value: GPIO_BIT!(panel.pins.chains[0].r1) }));
+    assert_eq!(Some(&RegisterWrite { word: GPSET0, value: GPIO_BIT!(panel.pins.oe) }), writes.last());
+}
@@ -0,0 +1,168 @@
+#[cfg(test)]
+use std::time::Instant;
+
+use super::frame::Frame;
+use super::gamma::ColorLut;
+use super::gpio_driver::MAX_PWM_BITS;
+use super::panel_config::PanelConfig;
+use super::pin_mapping::{ColorPins, PinMapping};
+use super::pixel::Pixel;
+#[cfg(test)]
+use super::pixel_mapper::IdentityMapper;
+
+/// The color bits of every column, plane and double row, ready to be written
+/// to the GPIO registers. Building it once per frame keeps the bit extraction
+/// out of the refresh loop, which runs hundreds of times a second.
+pub struct BitplaneBuffer {
+    columns: usize,
+    double_rows: usize,
+    words: Vec<u32>,
+}
+
+impl BitplaneBuffer {
+    pub fn new(config: &PanelConfig) -> BitplaneBuffer {
+        let columns = config.canvas_width();
+        let double_rows = config.scan_rate;
+        BitplaneBuffer {
+            columns,
+            double_rows,
+            words: vec![0; double_rows * MAX_PWM_BITS * columns],
+        }
+    }
+
+    /// Converts the frame, mapping every pixel through `color_lut` once.
+    pub fn fill(&mut self, frame: &Frame, config: &PanelConfig, mapping: &PinMapping, color_lut: &ColorLut) {
+        for word in self.words.iter_mut() {
+            *word = 0;
+        }
+        for row in 0..self.double_rows {
+            for chain in 0..config.parallel {
+                let top_row = chain * config.height + row;
+                for col in 0..self.columns {
+                    let pixel_top = color_lut.map(frame.physical_pixel(top_row, col));
+                    let pixel_bot = color_lut.map(frame.physical_pixel(top_row + config.scan_rate, col));
+                    for plane in 0..MAX_PWM_BITS {
+                        self.words[(row * MAX_PWM_BITS + plane) * self.columns + col] |=
+                            get_plane_bits(pixel_top, pixel_bot, plane, &mapping.chains[chain]);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The words to clock in, one per column, for a double row and plane.
+    pub fn plane(&self, double_row: usize, plane: usize) -> &[u32] {
+        let start = (double_row * MAX_PWM_BITS + plane) * self.columns;
+        &self.words[start..start + self.columns]
+    }
+}
+
+pub fn get_plane_bits(top: Pixel, bot: Pixel, plane: usize, pins: &ColorPins) -> u32 {
+    let mut out: u32 = 0;
+    if top.r & (1 << plane) != 0 {
+        out |= GPIO_BIT!(pins.r1);
+    }
+    if bot.r & (1 << plane) != 0 {
+        out |= GPIO_BIT!(pins.r2);
+    }
+    if top.b & (1 << plane) != 0 {
+        out |= GPIO_BIT!(pins.b1);
+    }
+    if bot.b & (1 << plane) != 0 {
+        out |= GPIO_BIT!(pins.b2);
+    }
+    if top.g & (1 << plane) != 0 {
+        out |= GPIO_BIT!(pins.g1);
+    }
+    if bot.g & (1 << plane) != 0 {
+        out |= GPIO_BIT!(pins.g2);
+    }
+    out
+}
+
+#[cfg(test)]
+fn test_frame(config: &PanelConfig) -> Frame {
+    let mut frame = Frame::with_mapper(config, &IdentityMapper).unwrap();
+    for (row, pixels) in frame.pixels.iter_mut().enumerate() {
+        for (col, pixel) in pixels.iter_mut().enumerate() {
+            *pixel = Pixel::new_colored_pixel((col * 4 % 256) as u16, (row * 8 % 256) as u16, ((row + col) % 256) as u16);
+        }
+    }
+    frame
+}
+
+// What send_values used to compute for every refresh.
+#[cfg(test)]
+fn plane_word(frame: &Frame, config: &PanelConfig, mapping: &PinMapping, color_lut: &ColorLut, row: usize, plane: usize, col: usize) -> u32 {
+    let mut word = 0;
+    for chain in 0..config.parallel {
+        let top_row = chain * config.height + row;
+        let pixel_top = color_lut.map(frame.physical_pixel(top_row, col));
+        let pixel_bot = color_lut.map(frame.physical_pixel(top_row + config.scan_rate, col));
+        word |= get_plane_bits(pixel_top, pixel_bot, plane, &mapping.chains[chain]);
+    }
+    word
+}
+
+#[test]
+fn bitplane_buffer_matches_per_pixel_bits_test() {
+    let mapping = PinMapping::regular();
+    let config = PanelConfig::default().chained(2, 2).unwrap();
+    let frame = test_frame(&config);
+    let color_lut = ColorLut::default();
+    let mut buffer = BitplaneBuffer::new(&config);
+    buffer.fill(&frame, &config, &mapping, &color_lut);
+
+    for row in 0..config.scan_rate {
+        for plane in 0..MAX_PWM_BITS {
+            let expected: Vec<u32> = (0..config.canvas_width())
+                .map(|col| plane_word(&frame, &config, &mapping, &color_lut, row, plane, col))
+                .collect();
+            assert_eq!(expected.as_slice(), buffer.plane(row, plane), "Wrong words for row {} plane {}", row, plane);
+        }
+    }
+}
+
+// Run with `cargo test --release -- --ignored --nocapture bitplane`.
+#[test]
+#[ignore]
+fn bitplane_buffer_benchmark() {
+    const REFRESHES: u32 = 200;
+    let mapping = PinMapping::regular();
+    let config = PanelConfig::new(64, 32, 16).unwrap().chained(4, 3).unwrap();
+    let frame = test_frame(&config);
+    let color_lut = ColorLut::default();
+    let (mut per_pixel_sum, mut buffered_sum) = (0u32, 0u32);
+
+    let start = Instant::now();
+    for _refresh in 0..REFRESHES {
+        for row in 0..config.scan_rate {
+            for plane in 0..MAX_PWM_BITS {
+                for col in 0..config.canvas_width() {
+                    per_pixel_sum = per_pixel_sum.wrapping_add(plane_word(&frame, &config, &mapping, &color_lut, row, plane, col));
+                }
+            }
+        }
+    }
+    let per_pixel = start.elapsed();
+
+    let start = Instant::now();
+    let mut buffer = BitplaneBuffer::new(&config);
+    buffer.fill(&frame, &config, &mapping, &color_lut);
+    let fill = start.elapsed();
+    for _refresh in 0..REFRESHES {
+        for row in 0..config.scan_rate {
+            for plane in 0..MAX_PWM_BITS {
+                for word in buffer.plane(row, plane) {
+                    buffered_sum = buffered_sum.wrapping_add(*word);
+                }
+            }
+        }
+    }
+    let buffered = start.elapsed();
+
+    println!("{} refreshes of {}x{}: per pixel {:?}, buffered {:?} (of which fill {:?})",
+             REFRESHES, config.canvas_width(), config.canvas_height(), per_pixel, buffered, fill);
+    assert_eq!(per_pixel_sum, buffered_sum);
+    assert!(buffered < per_pixel);
+}
@@ -0,0 +1,289 @@
+use std::io;
+use std::io::Read;
+
+use super::image::Image;
+use super::image_source::{blend_onto_black, ImageFormat, ImageSource, SourceError};
+use super::pixel::Pixel;
+
+const FILE_HEADER_SIZE: u32 = 14;
+const CORE_HEADER_SIZE: u32 = 12;
+const INFO_HEADER_SIZE: u32 = 40;
+const V2_HEADER_SIZE: u32 = 52;
+const V3_HEADER_SIZE: u32 = 56;
+const V5_HEADER_SIZE: u32 = 124;
+// Far larger than anything a panel can show, small enough to allocate.
+const MAX_DIMENSION: u32 = 1 << 15;
+
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+
+pub struct BmpSource<R: Read> {
+    reader: Option<R>,
+}
+
+impl<R: Read> BmpSource<R> {
+    pub fn new(reader: R) -> BmpSource<R> {
+        BmpSource {
+            reader: Some(reader),
+        }
+    }
+}
+
+impl<R: Read> ImageSource for BmpSource<R> {
+    // A BMP holds a single image.
+    fn next_image(&mut self) -> Result<Option<Image>, SourceError> {
+        let mut reader = match self.reader.take() {
+            Some(reader) => reader,
+            None => return Ok(None),
+        };
+        decode_bmp_image(&mut reader).map(Some)
+    }
+}
+
+struct BmpHeader {
+    width: u32,
+    height: u32,
+    bottom_up: bool,
+    bits_per_pixel: u16,
+    palette: Vec<Pixel>,
+    masks: [u32; 4],
+}
+
+fn decode_bmp_image<R: Read>(reader: &mut R) -> Result<Image, SourceError> {
+    let mut position: u32 = 0;
+    let (header, pixel_offset) = read_header(reader, &mut position)?;
+    if pixel_offset < position {
+        return Err(bmp_error("pixel data overlaps the header"));
+    }
+    skip(reader, pixel_offset - position)?;
+
+    // Rows are padded to a multiple of four bytes.
+    let row_size = (header.width as usize * header.bits_per_pixel as usize).div_ceil(32) * 4;
+    let mut row_buffer = vec![0; row_size];
+    let mut pixels: Vec<Vec<Pixel>> = Vec::new();
+    for _row in 0..header.height {
+        read_exact(reader, &mut row_buffer)?;
+        pixels.push(decode_row(&header, &row_buffer)?);
+    }
+    if header.bottom_up {
+        pixels.reverse();
+    }
+
+    Ok(Image {
+        width: header.width,
+        height: header.height,
+        pixels,
+    })
+}
+
+fn read_header<R: Read>(reader: &mut R, position: &mut u32) -> Result<(BmpHeader, u32), SourceError> {
+    let mut file_header = [0; FILE_HEADER_SIZE as usize];
+    read_exact(reader, &mut file_header)?;
+    if &file_header[0..2] != b"BM" {
+        return Err(bmp_error("missing BM signature"));
+    }
+    let pixel_offset = le_u32(&file_header[10..]);
+
+    let mut size_buffer = [0; 4];
+    read_exact(reader, &mut size_buffer)?;
+    let header_size = le_u32(&size_buffer);
+    if header_size != CORE_HEADER_SIZE && !(INFO_HEADER_SIZE..=V5_HEADER_SIZE).contains(&header_size) {
+        return Err(bmp_error(&format!("unsupported header size {}", header_size)));
+    }
+    let mut info = vec![0; header_size as usize - 4];
+    read_exact(reader, &mut info)?;
+    *position = FILE_HEADER_SIZE + header_size;
+
+    let (width, height, bits_per_pixel, compression, colors_used) = if header_size == CORE_HEADER_SIZE {
+        (le_u16(&info[0..]) as i32, le_u16(&info[2..]) as i32, le_u16(&info[6..]), BI_RGB, 0)
+    } else {
+        (le_u32(&info[0..]) as i32, le_u32(&info[4..]) as i32, le_u16(&info[10..]), le_u32(&info[12..]), le_u32(&info[28..]))
+    };
+    if width <= 0 || height == 0 || width as u32 > MAX_DIMENSION || height.unsigned_abs() > MAX_DIMENSION {
+        return Err(bmp_error(&format!("invalid size {}x{}", width, height)));
+    }
+
+    let mut masks = match bits_per_pixel {
+        16 => [0x7C00, 0x03E0, 0x001F, 0],
+        _ => [0x00FF0000, 0x0000FF00, 0x000000FF, 0],
+    };
+    match (compression, bits_per_pixel) {
+        (BI_RGB, 1) | (BI_RGB, 4) | (BI_RGB, 8) | (BI_RGB, 16) | (BI_RGB, 24) | (BI_RGB, 32) => {},
+        (BI_BITFIELDS, 16) | (BI_BITFIELDS, 32) => {
+            // A plain info header is followed by the masks, later versions include them.
+            if header_size == INFO_HEADER_SIZE {
+                let mut mask_buffer = [0; 12];
+                read_exact(reader, &mut mask_buffer)?;
+                *position += 12;
+                masks[0] = le_u32(&mask_buffer[0..]);
+                masks[1] = le_u32(&mask_buffer[4..]);
+                masks[2] = le_u32(&mask_buffer[8..]);
+            } else if header_size < V2_HEADER_SIZE {
+                return Err(bmp_error(&format!("header size {} is too small for the color masks", header_size)));
+            } else {
+                masks[0] = le_u32(&info[36..]);
+                masks[1] = le_u32(&info[40..]);
+                masks[2] = le_u32(&info[44..]);
+            }
+            if header_size >= V3_HEADER_SIZE {
+                masks[3] = le_u32(&info[48..]);
+            }
+        }
+        _ => return Err(bmp_error(&format!("unsupported {} bits per pixel with compression {}", bits_per_pixel, compression))),
+    }
+
+    let mut palette: Vec<Pixel> = Vec::new();
+    if bits_per_pixel <= 8 {
+        let entries = if colors_used == 0 || colors_used > 1 << bits_per_pixel { 1 << bits_per_pixel } else { colors_used };
+        let entry_size = if header_size == CORE_HEADER_SIZE { 3 } else { 4 };
+        let mut entry = vec![0; entry_size];
+        for _entry in 0..entries {
+            read_exact(reader, &mut entry)?;
+            palette.push(Pixel::new_colored_pixel(entry[2] as u16, entry[1] as u16, entry[0] as u16));
+        }
+        *position += entries * entry_size as u32;
+    }
+
+    Ok((BmpHeader {
+        width: width as u32,
+        height: height.unsigned_abs(),
+        bottom_up: height > 0,
+        bits_per_pixel,
+        palette,
+        masks,
+    }, pixel_offset))
+}
+
+fn decode_row(header: &BmpHeader, row: &[u8]) -> Result<Vec<Pixel>, SourceError> {
+    let mut pixels: Vec<Pixel> = Vec::new();
+    for col in 0..header.width as usize {
+        let pixel = match header.bits_per_pixel {
+            1 | 4 | 8 => {
+                let bits = header.bits_per_pixel as usize;
+                let bit_offset = col * bits;
+                let index = (row[bit_offset / 8] >> (8 - bits - bit_offset % 8)) & ((1 << bits) - 1) as u8;
+                match header.palette.get(index as usize) {
+                    Some(pixel) => *pixel,
+                    None => return Err(bmp_error(&format!("palette index {} out of range", index))),
+                }
+            }
+            24 => Pixel::new_colored_pixel(row[col * 3 + 2] as u16, row[col * 3 + 1] as u16, row[col * 3] as u16),
+            16 => masked_pixel(le_u16(&row[col * 2..]) as u32, &header.masks),
+            _ => masked_pixel(le_u32(&row[col * 4..]), &header.masks),
+        };
+        pixels.push(pixel);
+    }
+    Ok(pixels)
+}
+
+fn masked_pixel(value: u32, masks: &[u32; 4]) -> Pixel {
+    let r = masked_sample(value, masks[0]);
+    let g = masked_sample(value, masks[1]);
+    let b = masked_sample(value, masks[2]);
+    if masks[3] == 0 {
+        Pixel::new_colored_pixel(r as u16, g as u16, b as u16)
+    } else {
+        blend_onto_black(r, g, b, masked_sample(value, masks[3]))
+    }
+}
+
+// Extracts the bits selected by `mask` and scales them to a byte.
+fn masked_sample(value: u32, mask: u32) -> u8 {
+    if mask == 0 {
+        return 0;
+    }
+    let max_value = (mask >> mask.trailing_zeros()) as u64;
+    let sample = ((value & mask) >> mask.trailing_zeros()) as u64;
+    ((sample * 255 + max_value / 2) / max_value) as u8
+}
+
+fn skip<R: Read>(reader: &mut R, count: u32) -> Result<(), SourceError> {
+    let skipped = io::copy(&mut reader.take(count as u64), &mut io::sink())?;
+    if skipped < count as u64 {
+        return Err(bmp_error("file ends unexpectedly"));
+    }
+    Ok(())
+}
+
+fn read_exact<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<(), SourceError> {
+    match reader.read_exact(buffer) {
+        Ok(()) => Ok(()),
+        Err(ref why) if why.kind() == io::ErrorKind::UnexpectedEof => Err(bmp_error("file ends unexpectedly")),
+        Err(why) => Err(SourceError::Io(why)),
+    }
+}
+
+fn le_u16(bytes: &[u8]) -> u16 {
+    bytes[0] as u16 | (bytes[1] as u16) << 8
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+fn bmp_error(reason: &str) -> SourceError {
+    SourceError::Decode(ImageFormat::Bmp, String::from(reason))
+}
+
+#[test]
+fn bmp_source_paletted_top_down_test() {
+    use std::fs::File;
+
+    let mut source = BmpSource::new(File::open("resources/image_source/paletted_top_down.bmp").unwrap());
+    let image = source.next_image().unwrap().unwrap();
+
+    assert_eq!((3, 2), (image.width, image.height));
+    assert_eq!(vec![Pixel::new_colored_pixel(255, 0, 0), Pixel::new_colored_pixel(0, 255, 0), Pixel::new_colored_pixel(0, 0, 255)], image.pixels[0]);
+    assert_eq!(vec![Pixel::new_colored_pixel(255, 255, 255), Pixel::new(), Pixel::new_colored_pixel(128, 128, 128)], image.pixels[1]);
+}
+
+#[test]
+fn bmp_source_bitfields_with_alpha_test() {
+    use std::fs::File;
+
+    let mut source = BmpSource::new(File::open("resources/image_source/bitfields_alpha.bmp").unwrap());
+    let image = source.next_image().unwrap().unwrap();
+
+    assert_eq!(vec![Pixel::new_colored_pixel(100, 50, 25), Pixel::new_colored_pixel(10, 20, 30)], image.pixels[0]);
+}
+
+#[test]
+fn bmp_source_truncated_test() {
+    let mut source = BmpSource::new(io::Cursor::new(b"BM\x00\x00".to_vec()));
+    match source.next_image() {
+        Err(SourceError::Decode(ImageFormat::Bmp, _)) => {},
+        other => panic!("Expected a BMP decode error, got {:?}", other.map(|image| image.is_some())),
+    }
+}
+
+#[cfg(test)]
+fn bmp_with_header(header_size: u32, width: i32, height: i32, compression: u32) -> Vec<u8> {
+    let mut bytes = b"BM".to_vec();
+    bytes.extend_from_slice(&[0; 8]);
+    bytes.extend_from_slice(&FILE_HEADER_SIZE.wrapping_add(header_size).to_le_bytes());
+    bytes.extend_from_slice(&header_size.to_le_bytes());
+    bytes.extend_from_slice(&width.to_le_bytes());
+    bytes.extend_from_slice(&height.to_le_bytes());
+    bytes.extend_from_slice(&1u16.to_le_bytes());
+    bytes.extend_from_slice(&32u16.to_le_bytes());
+    bytes.extend_from_slice(&compression.to_le_bytes());
+    // Oversized headers are cut short, they are rejected before being read.
+    bytes.resize((FILE_HEADER_SIZE + header_size.min(V5_HEADER_SIZE)) as usize, 0);
+    bytes
+}
+
+#[test]
+fn bmp_source_malformed_header_test() {
+    let malformed = vec![
+        bmp_with_header(INFO_HEADER_SIZE, 1, i32::MIN, BI_RGB),
+        bmp_with_header(INFO_HEADER_SIZE, i32::MAX, 1, BI_RGB),
+        bmp_with_header(44, 1, 1, BI_BITFIELDS),
+        bmp_with_header(0xFFFF_FFF0, 1, 1, BI_RGB),
+    ];
+    for bytes in malformed {
+        match BmpSource::new(io::Cursor::new(bytes)).next_image() {
+            Err(SourceError::Decode(ImageFormat::Bmp, _)) => {},
+            other => panic!("Expected a BMP decode error, got {:?}", other.map(|image| image.is_some())),
+        }
+    }
+}
@@ -1,177 +1,468 @@
 use std::error::Error;
+use std::fmt;
 use std::fs::File;
-use std::io::{Cursor, Read};
-use std::num::ParseIntError;
+use std::io;
+use std::io::{BufReader, Read};
+#[cfg(test)]
+use std::io::Cursor;
 use std::path::Path;
 
+use super::gpio_driver::COLOR_DEPTH;
 use super::image::Image;
 use super::pixel::Pixel as Pixel;
 
-pub fn read_ppm_file(path: &Path) -> Image {
-    let display = path.display();
+// Anything larger is a corrupt header rather than a picture for a panel.
+const MAX_DIMENSION: u32 = 1 << 15;
+const MAX_HEADER_LINE: usize = 1024;
+
+#[derive(Debug)]
+pub enum PpmError {
+    Io(io::Error),
+    BadMagic(char, char),
+    Truncated,
+    BadNumber(String),
+    BadMaxValue(u32),
+    BadSize(u32, u32),
+    BadHeader(String),
+}
 
-    let mut file = match File::open(&path)    {
-        Err(why) => panic!("Could not open file: {} (Reason: {})",display, why.description()),
-        Ok(file) => file
-    };
+impl fmt::Display for PpmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PpmError::Io(why) => write!(f, "I/O error: {}", why),
+            PpmError::BadMagic(first, second) => write!(f, "Invalid Netpbm magic {:?}{:?}, expected \"P1\" to \"P7\"", first, second),
+            PpmError::Truncated => write!(f, "Netpbm data ends unexpectedly"),
+            PpmError::BadNumber(value) => write!(f, "Invalid number {:?} in Netpbm data", value),
+            PpmError::BadMaxValue(value) => write!(f, "Invalid max value {}, expected 1-65535", value),
+            PpmError::BadSize(width, height) => write!(f, "Invalid size {}x{}, expected at most {} pixels per side", width, height, MAX_DIMENSION),
+            PpmError::BadHeader(reason) => write!(f, "Invalid PAM header: {}", reason),
+        }
+    }
+}
+
+impl Error for PpmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PpmError::Io(why) => Some(why),
+            _ => None,
+        }
+    }
+}
 
-    let mut raw_file = Vec::new();
-    file.read_to_end(&mut raw_file).unwrap();
-    let mut cursor = Cursor::new(raw_file);
+impl From<io::Error> for PpmError {
+    fn from(why: io::Error) -> PpmError {
+        PpmError::Io(why)
+    }
+}
 
-    let image = match decode_ppm_image(&mut cursor) {
-        Ok(img) => img,
-        Err(why) => panic!("Could not parse PPM file - Desc: {}", why.description()),
-    };
-    image
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum NetpbmKind {
+    AsciiBitmap,
+    AsciiGraymap,
+    AsciiPixmap,
+    BinaryBitmap,
+    BinaryGraymap,
+    BinaryPixmap,
+    ArbitraryMap,
 }
 
-fn decode_ppm_image(cursor: &mut Cursor<Vec<u8>>) -> Result<Image, ParseIntError> {
+// Reads any Netpbm file: PBM (P1/P4), PGM (P2/P5), PPM (P3/P6) and PAM (P7).
+pub fn read_ppm_file(path: &Path) -> Result<Image, PpmError> {
+    let file = File::open(path)?;
+    decode_ppm_image(&mut BufReader::new(file))
+}
+
+/// Decodes a sequence of concatenated Netpbm images, such as the output of
+/// `ffmpeg -f image2pipe -vcodec ppm`, from any reader.
+pub struct PpmStreamReader<R: Read> {
+    reader: BufReader<R>,
+    finished: bool,
+}
+
+impl<R: Read> PpmStreamReader<R> {
+    pub fn new(reader: R) -> PpmStreamReader<R> {
+        PpmStreamReader {
+            reader: BufReader::new(reader),
+            finished: false,
+        }
+    }
+
+    /// Returns `Ok(None)` once the stream ends cleanly between two images.
+    pub fn next_image(&mut self) -> Result<Option<Image>, PpmError> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        let first_byte = match self.read_first_byte() {
+            Ok(Some(first_byte)) => first_byte,
+            Ok(None) => {
+                self.finished = true;
+                return Ok(None);
+            }
+            Err(why) => {
+                self.finished = true;
+                return Err(why);
+            }
+        };
+
+        let mut image_reader = io::Cursor::new([first_byte]).chain(&mut self.reader);
+        let image = decode_ppm_image(&mut image_reader);
+        if image.is_err() {
+            self.finished = true;
+        }
+        image.map(Some)
+    }
+
+    // Skips the whitespace some producers put between images.
+    fn read_first_byte(&mut self) -> Result<Option<u8>, PpmError> {
+        let mut buffer: [u8; 1] = [0];
+        loop {
+            match self.reader.read(&mut buffer) {
+                Ok(0) => return Ok(None),
+                Ok(_) if buffer[0].is_ascii_whitespace() => {},
+                Ok(_) => return Ok(Some(buffer[0])),
+                Err(ref why) if why.kind() == io::ErrorKind::Interrupted => {},
+                Err(why) => return Err(PpmError::Io(why)),
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for PpmStreamReader<R> {
+    type Item = Result<Image, PpmError>;
+
+    fn next(&mut self) -> Option<Result<Image, PpmError>> {
+        self.next_image().transpose()
+    }
+}
+
+fn decode_ppm_image<R: Read>(reader: &mut R) -> Result<Image, PpmError> {
+    let kind = read_constants(reader)?;
+    if kind == NetpbmKind::ArbitraryMap {
+        return decode_pam_image(reader);
+    }
+
     let mut image = Image {
         width: 0,
         height: 0,
         pixels: vec![]
     };
 
-    println!("Decode started");
-
-    read_constants(cursor);
+    read_size_or_comment(reader, &mut image)?;
+    check_size(image.width, image.height)?;
 
-    read_size_or_comment(cursor, &mut image);
-    println!("{}", image.width);
-    println!("{}", image.height);
-
-    let max_size = read_max_value_or_comment(cursor)?;
+    let max_size = match kind {
+        NetpbmKind::AsciiBitmap | NetpbmKind::BinaryBitmap => 1,
+        _ => read_max_value_or_comment(reader)?,
+    };
+    if max_size == 0 || max_size > 65535 {
+        return Err(PpmError::BadMaxValue(max_size));
+    }
 
     let mut pixels: Vec<Vec<Pixel>> = Vec::new();
 
-    if max_size <= 255 {
-        for _length in 0..image.height{
-            let mut row: Vec<Pixel> = Vec::new();
-            for _width in 0..image.width {
-                let pixel = build_pixel_u8(cursor);
-                row.push(pixel);
-            };
-            pixels.push(row);
-        };
-    } else {
-        for _length in 0..image.height{
-            let mut row: Vec<Pixel> = Vec::new();
-            for _width in 0..image.width {
-                let pixel = build_pixel_u16(cursor);
-                row.push(pixel);
-            };
-            pixels.push(row);
+    for _length in 0..image.height {
+        let row = match kind {
+            NetpbmKind::BinaryBitmap => read_bitmap_row(reader, image.width)?,
+            _ => {
+                let mut row: Vec<Pixel> = Vec::new();
+                for _width in 0..image.width {
+                    let pixel = build_netpbm_pixel(reader, kind, max_size)?;
+                    row.push(pixel);
+                };
+                row
+            }
         };
+        pixels.push(row);
     };
 
     image.pixels = pixels;
     Ok(image)
 }
 
-fn read_size_or_comment(cursor: &mut Cursor<Vec<u8>>, image: &mut Image) {
-    let first_character = read_char(cursor);
-    if first_character == '#' {
-        read_line(cursor);
-        read_size_or_comment(cursor, image);
+fn decode_pam_image<R: Read>(reader: &mut R) -> Result<Image, PpmError> {
+    let mut width: Option<u32> = None;
+    let mut height: Option<u32> = None;
+    let mut depth: Option<u32> = None;
+    let mut max_value: Option<u32> = None;
+
+    loop {
+        let line = read_header_line(reader)?;
+        let mut tokens = line.split_whitespace();
+        let key = match tokens.next() {
+            Some(key) => key,
+            None => continue,
+        };
+        if key.starts_with('#') || key == "TUPLTYPE" {
+            continue;
+        }
+        if key == "ENDHDR" {
+            break;
+        }
+
+        let value = tokens.next().unwrap_or("");
+        let number = value.parse::<u32>().map_err(|_err| PpmError::BadNumber(String::from(value)))?;
+        match key {
+            "WIDTH" => width = Some(number),
+            "HEIGHT" => height = Some(number),
+            "DEPTH" => depth = Some(number),
+            "MAXVAL" => max_value = Some(number),
+            _ => return Err(PpmError::BadHeader(format!("unknown header field {}", key))),
+        }
+    }
+
+    let missing = |field: &str| PpmError::BadHeader(format!("missing {}", field));
+    let width = width.ok_or_else(|| missing("WIDTH"))?;
+    let height = height.ok_or_else(|| missing("HEIGHT"))?;
+    let depth = depth.ok_or_else(|| missing("DEPTH"))?;
+    let max_value = max_value.ok_or_else(|| missing("MAXVAL"))?;
+    check_size(width, height)?;
+    if max_value == 0 || max_value > 65535 {
+        return Err(PpmError::BadMaxValue(max_value));
+    }
+    if depth == 0 || depth > 4 {
+        return Err(PpmError::BadHeader(format!("unsupported depth {}", depth)));
+    }
+
+    let mut pixels: Vec<Vec<Pixel>> = Vec::new();
+    for _length in 0..height {
+        let mut row: Vec<Pixel> = Vec::new();
+        for _width in 0..width {
+            let mut tuple: Vec<u16> = Vec::new();
+            for _sample in 0..depth {
+                tuple.push(read_binary_sample(reader, max_value)?);
+            }
+            row.push(build_pam_pixel(&tuple, max_value));
+        }
+        pixels.push(row);
+    }
+
+    Ok(Image {
+        width,
+        height,
+        pixels,
+    })
+}
+
+fn check_size(width: u32, height: u32) -> Result<(), PpmError> {
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(PpmError::BadSize(width, height));
     }
-    else {
-        image.width = read_size_propertie(cursor, Option::Some(first_character)).unwrap();
-        image.height = read_size_propertie(cursor, Option::None).unwrap();
+    Ok(())
+}
+
+// Depth 1 and 2 are greyscale, 3 and 4 are RGB. An alpha channel is blended
+// onto black, since that is what an unlit LED looks like.
+fn build_pam_pixel(tuple: &[u16], max_value: u32) -> Pixel {
+    let (r, g, b) = match tuple.len() {
+        1 | 2 => (tuple[0], tuple[0], tuple[0]),
+        _ => (tuple[0], tuple[1], tuple[2]),
+    };
+    let alpha = if tuple.len() == 2 || tuple.len() == 4 {
+        (*tuple.last().unwrap() as u32).min(max_value)
+    } else {
+        max_value
+    };
+    let blend = |value: u16| scale_sample(value as u32 * alpha / max_value, max_value);
+    Pixel::new_colored_pixel(blend(r), blend(g), blend(b))
+}
+
+// Rescales a sample from 0..=max_value to the 0..=2^COLOR_DEPTH-1 range the
+// panel works with, rounding to the nearest level.
+fn scale_sample(value: u32, max_value: u32) -> u16 {
+    let max_level = (1u32 << COLOR_DEPTH) - 1;
+    let value = value.min(max_value);
+    ((value * max_level + max_value / 2) / max_value) as u16
+}
+
+fn build_netpbm_pixel<R: Read>(reader: &mut R, kind: NetpbmKind, max_value: u32) -> Result<Pixel, PpmError> {
+    match kind {
+        NetpbmKind::AsciiBitmap => {
+            let value = bit_to_sample(read_ascii_bit(reader)?);
+            Ok(Pixel::new_colored_pixel(value, value, value))
+        }
+        NetpbmKind::AsciiGraymap => {
+            let value = scale_sample(read_ascii_sample(reader)? as u32, max_value);
+            Ok(Pixel::new_colored_pixel(value, value, value))
+        }
+        NetpbmKind::AsciiPixmap => Ok(Pixel {
+            r: scale_sample(read_ascii_sample(reader)? as u32, max_value),
+            g: scale_sample(read_ascii_sample(reader)? as u32, max_value),
+            b: scale_sample(read_ascii_sample(reader)? as u32, max_value)
+        }),
+        NetpbmKind::BinaryGraymap => {
+            let value = scale_sample(read_binary_sample(reader, max_value)? as u32, max_value);
+            Ok(Pixel::new_colored_pixel(value, value, value))
+        }
+        NetpbmKind::BinaryPixmap => build_pixel(reader, max_value),
+        NetpbmKind::BinaryBitmap | NetpbmKind::ArbitraryMap => unreachable!(),
+    }
+}
+
+// In a bitmap a set bit is black.
+fn bit_to_sample(bit: bool) -> u16 {
+    scale_sample(if bit { 0 } else { 1 }, 1)
+}
+
+// P4 packs eight pixels per byte, most significant bit first, and every row
+// starts on a new byte.
+fn read_bitmap_row<R: Read>(reader: &mut R, width: u32) -> Result<Vec<Pixel>, PpmError> {
+    let mut packed = vec![0; (width as usize).div_ceil(8)];
+    read_exact(reader, &mut packed)?;
+    Ok((0..width as usize)
+        .map(|x| {
+            let value = bit_to_sample(packed[x / 8] & (0x80 >> (x % 8)) != 0);
+            Pixel::new_colored_pixel(value, value, value)
+        })
+        .collect())
+}
+
+fn read_ascii_bit<R: Read>(reader: &mut R) -> Result<bool, PpmError> {
+    loop {
+        match read_char(reader)? {
+            '0' => return Ok(false),
+            '1' => return Ok(true),
+            '#' => read_line(reader)?,
+            value if value.is_ascii_whitespace() => {},
+            value => return Err(PpmError::BadNumber(value.to_string())),
+        }
     }
 }
 
-fn read_max_value_or_comment(cursor: &mut Cursor<Vec<u8>>) -> Result<u32, ParseIntError> {
-    let first_character = read_char(cursor);
-    if first_character == '#' {
-        read_line(cursor);
-        return read_max_value_or_comment(cursor);
+fn read_ascii_sample<R: Read>(reader: &mut R) -> Result<u16, PpmError> {
+    let value = read_number(reader)?;
+    if value > 65535 {
+        return Err(PpmError::BadNumber(value.to_string()));
     }
-    read_size_propertie(cursor, Option::Some(first_character))
+    Ok(value as u16)
 }
 
-fn read_line(cursor: &mut Cursor<Vec<u8>>) {
+fn read_binary_sample<R: Read>(reader: &mut R, max_value: u32) -> Result<u16, PpmError> {
+    if max_value <= 255 {
+        Ok(read_u8(reader)? as u16)
+    } else {
+        read_u16(reader)
+    }
+}
+
+fn read_header_line<R: Read>(reader: &mut R) -> Result<String, PpmError> {
     let mut result_buffer: Vec<char> = Vec::new();
     loop {
-        let value = read_char(cursor);
+        let value = read_char(reader)?;
+        if value == '\n' {
+            return Ok(result_buffer.into_iter().collect());
+        }
+        if result_buffer.len() == MAX_HEADER_LINE {
+            return Err(PpmError::BadHeader(format!("line longer than {} bytes", MAX_HEADER_LINE)));
+        }
         result_buffer.push(value);
+    }
+}
+
+fn read_size_or_comment<R: Read>(reader: &mut R, image: &mut Image) -> Result<(), PpmError> {
+    image.width = read_number(reader)?;
+    image.height = read_number(reader)?;
+    Ok(())
+}
+
+fn read_max_value_or_comment<R: Read>(reader: &mut R) -> Result<u32, PpmError> {
+    read_number(reader)
+}
+
+// Skips whitespace and comments, then reads a decimal number up to and
+// including the whitespace character that ends it.
+fn read_number<R: Read>(reader: &mut R) -> Result<u32, PpmError> {
+    loop {
+        let first_character = read_char(reader)?;
+        if first_character == '#' {
+            read_line(reader)?;
+        } else if !first_character.is_ascii_whitespace() {
+            return read_size_propertie(reader, Option::Some(first_character));
+        }
+    }
+}
+
+fn read_line<R: Read>(reader: &mut R) -> Result<(), PpmError> {
+    loop {
+        let value = read_char(reader)?;
         if value == '\n' || value == '\r' {
-            break;
+            return Ok(());
         }
     }
 }
 
-fn read_size_propertie(cursor: &mut Cursor<Vec<u8>>, first_character: Option<char>) -> Result<u32, ParseIntError> {
+fn read_size_propertie<R: Read>(reader: &mut R, first_character: Option<char>) -> Result<u32, PpmError> {
     let mut result_buffer: Vec<char> = Vec::new();
     if first_character.is_some() {
         result_buffer.push(first_character.unwrap());
     }
-    read_until_split_character(cursor, &mut result_buffer);
+    read_until_split_character(reader, &mut result_buffer)?;
     let result_string: String = result_buffer.into_iter().collect();
-    result_string.parse::<u32>()
+    result_string.parse::<u32>().map_err(|_err| PpmError::BadNumber(result_string))
 }
 
-fn read_until_split_character(cursor: &mut Cursor<Vec<u8>>, result_buffer: &mut Vec<char>) {
+fn read_until_split_character<R: Read>(reader: &mut R, result_buffer: &mut Vec<char>) -> Result<(), PpmError> {
     loop {
-        let value = read_char(cursor);
+        let value = match read_char(reader) {
+            Err(PpmError::Truncated) if !result_buffer.is_empty() => return Ok(()),
+            value => value?,
+        };
         let condition = value == ' ' || value == '\n' || value == '\t' || value == '\r';
         if condition {
-            break;
+            return Ok(());
         };
         result_buffer.push(value);
     };
 }
 
-fn read_char(cursor: &mut Cursor<Vec<u8>>) -> char{
-    let mut buffer: [u8; 1] = [0];
-    match cursor.read(&mut buffer) {
-        Ok(buffer) => buffer,
-        Err(_err) => panic!("Reading of characters failed!")
-    };
-    buffer[0] as char
-}
-
-fn read_constants(cursor: &mut Cursor<Vec<u8>>){
-    assert_eq!(read_char(cursor), 'P', "Invalid header");
-    assert_eq!(read_char(cursor), '6', "Invalid PPM type");
-    read_char(cursor);
+fn read_char<R: Read>(reader: &mut R) -> Result<char, PpmError> {
+    Ok(read_u8(reader)? as char)
 }
 
-fn build_pixel_u16(cursor: &mut Cursor<Vec<u8>>) -> Pixel {
-    Pixel {
-        r: read_u16(cursor),
-        g: read_u16(cursor),
-        b: read_u16(cursor)
-    }
+fn read_constants<R: Read>(reader: &mut R) -> Result<NetpbmKind, PpmError> {
+    let first = read_char(reader)?;
+    let second = read_char(reader)?;
+    let kind = match (first, second) {
+        ('P', '1') => NetpbmKind::AsciiBitmap,
+        ('P', '2') => NetpbmKind::AsciiGraymap,
+        ('P', '3') => NetpbmKind::AsciiPixmap,
+        ('P', '4') => NetpbmKind::BinaryBitmap,
+        ('P', '5') => NetpbmKind::BinaryGraymap,
+        ('P', '6') => NetpbmKind::BinaryPixmap,
+        ('P', '7') => NetpbmKind::ArbitraryMap,
+        _ => return Err(PpmError::BadMagic(first, second)),
+    };
+    read_char(reader)?;
+    Ok(kind)
 }
 
-fn build_pixel_u8(cursor: &mut Cursor<Vec<u8>>) -> Pixel {
-    Pixel {
-        r: read_u8(cursor) as u16,
-        g: read_u8(cursor) as u16,
-        b: read_u8(cursor) as u16
-    }
+fn build_pixel<R: Read>(reader: &mut R, max_value: u32) -> Result<Pixel, PpmError> {
+    Ok(Pixel {
+        r: scale_sample(read_binary_sample(reader, max_value)? as u32, max_value),
+        g: scale_sample(read_binary_sample(reader, max_value)? as u32, max_value),
+        b: scale_sample(read_binary_sample(reader, max_value)? as u32, max_value)
+    })
 }
 
-fn read_u16(cursor: &mut Cursor<Vec<u8>>) -> u16 {
+// Samples wider than a byte are stored big-endian.
+fn read_u16<R: Read>(reader: &mut R) -> Result<u16, PpmError> {
     let mut buffer: [u8; 2] = [0,0];
-    match cursor.read(&mut buffer) {
-        Ok(buffer) => buffer,
-        Err(_err) => panic!("Reading of u16 failed!")
-    };
-    let result_string: String = buffer.to_vec().into_iter()
-        .map(|value| value as char)
-        .collect();
-    result_string.parse::<u16>().unwrap()
+    read_exact(reader, &mut buffer)?;
+    Ok((buffer[0] as u16) << 8 | buffer[1] as u16)
 }
 
-fn read_u8(cursor: &mut Cursor<Vec<u8>>) -> u8{
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8, PpmError> {
     let mut buffer: [u8; 1] = [0];
-    match cursor.read(&mut buffer) {
-        Ok(buffer) => buffer,
-        Err(_err) => panic!("Reading of u8 failed!")
-    };
-    buffer[0]
+    read_exact(reader, &mut buffer)?;
+    Ok(buffer[0])
+}
+
+fn read_exact<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<(), PpmError> {
+    match reader.read_exact(buffer) {
+        Ok(()) => Ok(()),
+        Err(ref why) if why.kind() == io::ErrorKind::UnexpectedEof => Err(PpmError::Truncated),
+        Err(why) => Err(PpmError::Io(why)),
+    }
 }
 
 
@@ -179,15 +470,15 @@ fn read_u8(cursor: &mut Cursor<Vec<u8>>) -> u8{
 fn read_file_header_test_P_values() {
     let vector: Vec<u8> = vec!['P' as u8, '6' as u8, '\n' as u8];
     let mut cursor: Cursor<Vec<u8>> = Cursor::new(vector);
-    read_constants(&mut cursor);
+    read_constants(&mut cursor).unwrap();
 }
 
 #[test]
 fn read_file_header_test_width_and_height_no_comments() {
     let vector: Vec<u8> = vec!['3' as u8, '2' as u8, ' ' as u8, '1' as u8, '6' as u8, '\n' as u8];
     let mut cursor: Cursor<Vec<u8>> = Cursor::new(vector);
-    let mut image : Image = Image::new();
-    read_size_or_comment(&mut cursor, &mut image);
+    let mut image = Image { width: 0, height: 0, pixels: Vec::new() };
+    read_size_or_comment(&mut cursor, &mut image).unwrap();
 
     assert_eq!(32, image.width ,"Image width is not 32");
     assert_eq!(16, image.height ,"Image height is not 16");
@@ -198,8 +489,8 @@ fn read_file_header_test_width_and_height_with_comments() {
     let vector: Vec<u8> = vec!['#' as u8, 'M' as u8, 'y' as u8, ' ' as u8, 'c' as u8, 'o' as u8, 'm' as u8, 'm' as u8, 'e' as u8, 'n' as u8, 't' as u8, '\n' as u8,
         '3' as u8, '2' as u8, ' ' as u8, '1' as u8, '6' as u8, '\n' as u8];
     let mut cursor: Cursor<Vec<u8>> = Cursor::new(vector);
-    let mut image : Image = Image::new();
-    read_size_or_comment(&mut cursor, &mut image);
+    let mut image = Image { width: 0, height: 0, pixels: Vec::new() };
+    read_size_or_comment(&mut cursor, &mut image).unwrap();
 
     assert_eq!(32, image.width ,"Image width is not 32");
     assert_eq!(16, image.height ,"Image height is not 16");
@@ -209,7 +500,6 @@ fn read_file_header_test_width_and_height_with_comments() {
 fn read_file_header_test_max_value_no_comments() {
     let vector: Vec<u8> = vec!['2' as u8, '5' as u8, '5' as u8, '\n' as u8];
     let mut cursor: Cursor<Vec<u8>> = Cursor::new(vector);
-    let mut image : Image = Image::new();
     let result = read_max_value_or_comment(&mut cursor);
 
     assert_eq!(255, result.unwrap() ,"The right max value was not found");
@@ -220,7 +510,6 @@ fn read_file_header_test_max_value_with_comments() {
     let vector: Vec<u8> = vec!['#' as u8, 'M' as u8, 'y' as u8, ' ' as u8, 'c' as u8, 'o' as u8, 'm' as u8, 'm' as u8, 'e' as u8, 'n' as u8, 't' as u8, '\n' as u8,
                                '2' as u8, '5' as u8, '5' as u8, '\n' as u8];
     let mut cursor: Cursor<Vec<u8>> = Cursor::new(vector);
-    let mut image : Image = Image::new();
     let result = read_max_value_or_comment(&mut cursor);
 
     assert_eq!(255, result.unwrap() ,"The right max value was not found");
@@ -229,7 +518,7 @@ fn read_file_header_test_max_value_with_comments() {
 #[test]
 fn read_file_header_integration_test_no_comments() {
     let path = Path::new("resources/testfile_no_comments.ppm");
-    let image = read_ppm_file(&path);
+    let image = read_ppm_file(&path).unwrap();
 
     assert_eq!(32, image.width ,"Image width is not 32");
     assert_eq!(16, image.height ,"Image height is not 16");
@@ -238,8 +527,169 @@ fn read_file_header_integration_test_no_comments() {
 #[test]
 fn read_file_header_integration_test_with_comments() {
     let path = Path::new("resources/testfile_with_comments.ppm");
-    let image = read_ppm_file(&path);
+    let image = read_ppm_file(&path).unwrap();
 
     assert_eq!(32, image.width ,"Image width is not 32");
     assert_eq!(16, image.height ,"Image height is not 16");
 }
+
+#[test]
+fn read_file_header_test_bad_magic() {
+    let vector: Vec<u8> = vec!['P' as u8, '8' as u8, '\n' as u8];
+    let mut cursor: Cursor<Vec<u8>> = Cursor::new(vector);
+    match read_constants(&mut cursor) {
+        Err(PpmError::BadMagic('P', '8')) => {},
+        other => panic!("Expected a bad magic error, got {:?}", other),
+    }
+}
+
+#[test]
+fn read_file_test_truncated_data() {
+    let mut cursor: Cursor<Vec<u8>> = Cursor::new(b"P6\n2 2\n255\n\x01\x02\x03".to_vec());
+    match decode_ppm_image(&mut cursor) {
+        Err(PpmError::Truncated) => {},
+        other => panic!("Expected a truncated error, got {:?}", other.map(|image| image.width)),
+    }
+}
+
+#[test]
+fn read_file_test_bad_max_value() {
+    let mut cursor: Cursor<Vec<u8>> = Cursor::new(b"P6\n1 1\n70000\n".to_vec());
+    match decode_ppm_image(&mut cursor) {
+        Err(PpmError::BadMaxValue(70000)) => {},
+        other => panic!("Expected a bad max value error, got {:?}", other.map(|image| image.width)),
+    }
+}
+
+#[test]
+fn read_file_test_oversized_images() {
+    let mut cursor: Cursor<Vec<u8>> = Cursor::new(b"P4\n4294967295 1\n".to_vec());
+    match decode_ppm_image(&mut cursor) {
+        Err(PpmError::BadSize(4294967295, 1)) => {},
+        other => panic!("Expected a bad size error, got {:?}", other.map(|image| image.width)),
+    }
+
+    let mut cursor: Cursor<Vec<u8>> = Cursor::new(b"P7\nWIDTH 1\nHEIGHT 100000\nDEPTH 3\nMAXVAL 255\nENDHDR\n".to_vec());
+    match decode_ppm_image(&mut cursor) {
+        Err(PpmError::BadSize(1, 100000)) => {},
+        other => panic!("Expected a bad size error, got {:?}", other.map(|image| image.width)),
+    }
+
+    let mut header = b"P7\n#".to_vec();
+    header.extend(vec![b' '; 1 << 20]);
+    match decode_ppm_image(&mut Cursor::new(header)) {
+        Err(PpmError::BadHeader(_)) => {},
+        other => panic!("Expected a bad header error, got {:?}", other.map(|image| image.width)),
+    }
+}
+
+#[test]
+fn read_file_test_missing_file() {
+    match read_ppm_file(Path::new("resources/does_not_exist.ppm")) {
+        Err(PpmError::Io(_)) => {},
+        other => panic!("Expected an I/O error, got {:?}", other.map(|image| image.width)),
+    }
+}
+
+#[cfg(test)]
+fn read_netpbm_fixture(name: &str) -> Image {
+    read_ppm_file(&Path::new("resources/netpbm").join(name)).unwrap()
+}
+
+#[cfg(test)]
+fn grey(value: u16) -> Pixel {
+    Pixel::new_colored_pixel(value, value, value)
+}
+
+#[test]
+fn read_file_test_bitmaps() {
+    for name in &["bitmap_ascii.pbm", "bitmap_binary.pbm"] {
+        let image = read_netpbm_fixture(name);
+        assert_eq!((3, 2), (image.width, image.height), "Wrong size for {}", name);
+        assert_eq!(vec![grey(0), grey(255), grey(0)], image.pixels[0], "Wrong first row for {}", name);
+        assert_eq!(vec![grey(255), grey(0), grey(255)], image.pixels[1], "Wrong second row for {}", name);
+    }
+}
+
+#[test]
+fn read_file_test_graymaps() {
+    for name in &["graymap_ascii.pgm", "graymap_binary.pgm"] {
+        let image = read_netpbm_fixture(name);
+        assert_eq!(vec![grey(0), grey(128), grey(255)], image.pixels[0], "Wrong first row for {}", name);
+        assert_eq!(vec![grey(64), grey(32), grey(16)], image.pixels[1], "Wrong second row for {}", name);
+    }
+}
+
+#[test]
+fn read_file_test_pixmaps() {
+    for name in &["pixmap_ascii.ppm", "pixmap_binary.ppm"] {
+        let image = read_netpbm_fixture(name);
+        assert_eq!(vec![Pixel::new_colored_pixel(255, 0, 0), Pixel::new_colored_pixel(0, 255, 0), Pixel::new_colored_pixel(0, 0, 255)],
+                   image.pixels[0], "Wrong first row for {}", name);
+        assert_eq!(vec![grey(255), grey(0), grey(128)], image.pixels[1], "Wrong second row for {}", name);
+    }
+}
+
+#[test]
+fn read_file_test_pam_alpha_is_blended_onto_black() {
+    let image = read_netpbm_fixture("rgb_alpha.pam");
+    assert_eq!((2, 1), (image.width, image.height));
+    assert_eq!(vec![Pixel::new_colored_pixel(100, 50, 25), Pixel::new_colored_pixel(10, 20, 30)], image.pixels[0]);
+
+    let image = read_netpbm_fixture("grayscale_alpha.pam");
+    assert_eq!(vec![grey(0), grey(100)], image.pixels[0]);
+}
+
+#[test]
+fn read_file_test_pam_missing_header_field() {
+    let mut cursor: Cursor<Vec<u8>> = Cursor::new(b"P7\nWIDTH 1\nHEIGHT 1\nMAXVAL 255\nENDHDR\n\x00".to_vec());
+    match decode_ppm_image(&mut cursor) {
+        Err(PpmError::BadHeader(_)) => {},
+        other => panic!("Expected a bad header error, got {:?}", other.map(|image| image.width)),
+    }
+}
+
+#[test]
+fn read_file_test_max_values_are_scaled() {
+    let cases = [
+        (1, Pixel::new_colored_pixel(255, 0, 255)),
+        (15, Pixel::new_colored_pixel(85, 170, 255)),
+        (255, Pixel::new_colored_pixel(1, 128, 254)),
+        (1023, Pixel::new_colored_pixel(127, 64, 249)),
+        (65535, Pixel::new_colored_pixel(100, 128, 0)),
+    ];
+    for (max_value, expected) in cases.iter() {
+        let image = read_netpbm_fixture(&format!("maxval_{}.ppm", max_value));
+        assert_eq!(vec![grey(255), grey(0), *expected], image.pixels[0], "Wrong pixels for max value {}", max_value);
+    }
+}
+
+#[test]
+fn read_u16_is_big_endian_test() {
+    let mut cursor: Cursor<Vec<u8>> = Cursor::new(vec![0x12, 0x34]);
+    assert_eq!(0x1234, read_u16(&mut cursor).unwrap());
+}
+
+#[test]
+fn ppm_stream_reader_test() {
+    let mut stream: Vec<u8> = Vec::new();
+    stream.extend_from_slice(b"P6\n1 1\n255\n\x01\x02\x03");
+    stream.extend_from_slice(b"P5\n2 1\n255\n\x04\x05\n");
+    stream.extend_from_slice(b"P3\n1 1\n255\n6 7 8\n");
+    let images: Vec<Image> = PpmStreamReader::new(Cursor::new(stream)).map(|image| image.unwrap()).collect();
+
+    assert_eq!(3, images.len());
+    assert_eq!(vec![Pixel::new_colored_pixel(1, 2, 3)], images[0].pixels[0]);
+    assert_eq!(vec![grey(4), grey(5)], images[1].pixels[0]);
+    assert_eq!(vec![Pixel::new_colored_pixel(6, 7, 8)], images[2].pixels[0]);
+}
+
+#[test]
+fn ppm_stream_reader_stops_after_error_test() {
+    let mut reader = PpmStreamReader::new(Cursor::new(b"P6\n2 1\n255\n\x01\x02\x03".to_vec()));
+    match reader.next_image() {
+        Err(PpmError::Truncated) => {},
+        other => panic!("Expected a truncated error, got {:?}", other.map(|image| image.is_some())),
+    }
+    assert!(reader.next_image().unwrap().is_none());
+}
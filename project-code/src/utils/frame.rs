@@ -1,35 +1,85 @@
 use std::path::Path;
 
 use super::file_reader::read_ppm_file;
-use super::gpio_driver::COLUMNS;
-use super::gpio_driver::ROWS;
 use super::image::Image;
+use super::panel_config::PanelConfig;
 use super::pixel::Pixel;
+use super::pixel_mapper::PixelMapper;
+#[cfg(test)]
+use super::pixel_mapper::IdentityMapper;
 
 const GAME_OVER_IMG_PATH: &str = "resources/snake/game_over.ppm";
 
 pub struct Frame {
     BORDER_PIXEL: Pixel,
     pos: usize,
+    pub width: usize,
+    pub height: usize,
     pub pixels: Vec<Vec<Pixel>>,
+    physical_map: Vec<Vec<(usize, usize)>>,
 }
 
 impl Frame {
-    pub fn new() -> Frame {
-        let frame: Frame = Frame {
+    /// Fails if `mapper` does not map the logical canvas one to one onto the
+    /// physical one.
+    pub fn with_mapper(config: &PanelConfig, mapper: &dyn PixelMapper) -> Result<Frame, String> {
+        mapper.check(config)?;
+        let (width, height) = mapper.logical_size(config);
+        let mut physical_map: Vec<Vec<Option<(usize, usize)>>> = vec![vec![None; config.canvas_width()]; config.canvas_height()];
+        for row in 0..height {
+            for col in 0..width {
+                let (x, y) = mapper.map(config, col, row);
+                match physical_map.get_mut(y).and_then(|physical_row| physical_row.get_mut(x)) {
+                    Some(Some(_)) => return Err(format!("Pixel mapper maps two pixels onto ({}, {})", x, y)),
+                    Some(position) => *position = Some((row, col)),
+                    None => return Err(format!("Pixel mapper maps ({}, {}) outside the panels", x, y)),
+                }
+            }
+        }
+        let physical_map = physical_map.into_iter()
+            .map(|physical_row| physical_row.into_iter().collect::<Option<Vec<(usize, usize)>>>())
+            .collect::<Option<Vec<Vec<(usize, usize)>>>>()
+            .ok_or_else(|| String::from("Pixel mapper leaves physical pixels unmapped"))?;
+
+        Ok(Frame {
             BORDER_PIXEL: Pixel::new_colored_pixel(230, 230, 230),
             pos: 0,
-            pixels: vec![vec![Pixel::new(); COLUMNS as usize]; ROWS as usize],
-        };
-        frame
+            width,
+            height,
+            pixels: vec![vec![Pixel::new(); width]; height],
+            physical_map,
+        })
+    }
+
+    /// A copy of the logical pixels.
+    pub fn to_image(&self) -> Image {
+        Image {
+            width: self.width as u32,
+            height: self.height as u32,
+            pixels: self.pixels.clone(),
+        }
+    }
+
+    pub fn physical_pixel(&self, row: usize, col: usize) -> Pixel {
+        let (logical_row, logical_col) = self.physical_map[row][col];
+        self.pixels[logical_row][logical_col]
     }
 
+    // Scrolls horizontally through the image. Rows the image does not have
+    // are left black; use resample::fit_image to match the panel height.
     pub fn next_image_frame(&mut self, image: &Image) {
-        for row in 0..ROWS {
-            for col in 0..COLUMNS {
+        if image.width == 0 {
+            self.clear_frame();
+            return;
+        }
+        for row in 0..self.height {
+            for col in 0..self.width {
                 let img_pos = (self.pos + col) % image.width as usize;
 
-                self.pixels[row][col] = image.pixels[row][img_pos].clone();
+                self.pixels[row][col] = match image.pixels.get(row).and_then(|pixels| pixels.get(img_pos)) {
+                    Some(pixel) => *pixel,
+                    None => Pixel::new(),
+                };
             }
         }
 
@@ -39,10 +89,24 @@ impl Frame {
         }
     }
 
+    // Shows an image without scrolling. Parts of the frame the image does not
+    // cover are left black.
+    pub fn show_image(&mut self, image: &Image) {
+        self.pos = 0;
+        for row in 0..self.height {
+            for col in 0..self.width {
+                self.pixels[row][col] = match image.pixels.get(row).and_then(|pixels| pixels.get(col)) {
+                    Some(pixel) => *pixel,
+                    None => Pixel::new(),
+                };
+            }
+        }
+    }
+
     pub fn draw_border(self: &mut Frame) {
-        for row in 0..ROWS {
-            for col in 0..COLUMNS {
-                if row == 0 || row == ROWS -1|| col == 0 || col == COLUMNS-1 {
+        for row in 0..self.height {
+            for col in 0..self.width {
+                if row == 0 || row == self.height - 1 || col == 0 || col == self.width - 1 {
                     self.pixels[row][col] = self.BORDER_PIXEL;
                 }
             }
@@ -50,10 +114,77 @@ impl Frame {
     }
 
     pub fn draw_game_over(self: &mut Frame) {
-        self.next_image_frame(&read_ppm_file(&Path::new(GAME_OVER_IMG_PATH)));
+        match read_ppm_file(Path::new(GAME_OVER_IMG_PATH)) {
+            Ok(image) => self.next_image_frame(&image),
+            Err(why) => eprintln!("Could not load {}: {}", GAME_OVER_IMG_PATH, why),
+        }
     }
 
     pub fn clear_frame(self:&mut Frame){
-        self.pixels=vec![vec![Pixel::new(); COLUMNS as usize]; ROWS as usize];
+        self.pixels=vec![vec![Pixel::new(); self.width]; self.height];
+    }
+}
+
+#[test]
+fn physical_pixel_follows_mapper_test() {
+    use super::pixel_mapper::UpsideDownMapper;
+
+    let config = PanelConfig::default().chained(2, 1).unwrap();
+    let mut frame = Frame::with_mapper(&config, &UpsideDownMapper).unwrap();
+    frame.pixels[0][0] = Pixel::new_colored_pixel(255, 0, 0);
+
+    assert_eq!(64, frame.width);
+    assert_eq!(255, frame.physical_pixel(15, 63).r);
+    assert_eq!(0, frame.physical_pixel(0, 0).r);
+}
+
+#[test]
+fn with_mapper_rejects_bad_mappers_test() {
+    use super::pixel_mapper::SnakeMapper;
+
+    // Every pixel onto the same LED.
+    struct CornerMapper;
+    impl PixelMapper for CornerMapper {
+        fn logical_size(&self, config: &PanelConfig) -> (usize, usize) {
+            (config.canvas_width(), config.canvas_height())
+        }
+
+        fn map(&self, _config: &PanelConfig, _x: usize, _y: usize) -> (usize, usize) {
+            (0, 0)
+        }
     }
-}
\ No newline at end of file
+
+    assert!(Frame::with_mapper(&PanelConfig::default(), &CornerMapper).is_err());
+    assert!(Frame::with_mapper(&PanelConfig::default(), &SnakeMapper { rows: 2 }).is_err());
+}
+
+#[test]
+fn show_image_pads_small_images_test() {
+    let mut frame = Frame::with_mapper(&PanelConfig::default(), &IdentityMapper).unwrap();
+    frame.pixels[15][31] = Pixel::new_colored_pixel(1, 1, 1);
+    let image = Image {
+        width: 1,
+        height: 1,
+        pixels: vec![vec![Pixel::new_colored_pixel(255, 0, 0)]],
+    };
+    frame.show_image(&image);
+
+    assert_eq!(Pixel::new_colored_pixel(255, 0, 0), frame.pixels[0][0]);
+    assert_eq!(Pixel::new(), frame.pixels[15][31]);
+}
+
+#[test]
+fn next_image_frame_short_image_test() {
+    let mut frame = Frame::with_mapper(&PanelConfig::default(), &IdentityMapper).unwrap();
+    let image = Image {
+        width: 2,
+        height: 1,
+        pixels: vec![vec![Pixel::new_colored_pixel(255, 0, 0), Pixel::new_colored_pixel(0, 255, 0)]],
+    };
+    frame.next_image_frame(&image);
+    frame.next_image_frame(&image);
+
+    assert_eq!(Pixel::new_colored_pixel(0, 255, 0), frame.pixels[0][0]);
+    assert_eq!(Pixel::new_colored_pixel(255, 0, 0), frame.pixels[0][1]);
+    assert_eq!(Pixel::new(), frame.pixels[15][0]);
+}
@@ -0,0 +1,177 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::gpio_driver::{COLOR_DEPTH, MAX_PWM_BITS};
+use super::pixel::Pixel;
+
+/// Maps a channel value to the fraction of the time an LED should be on. LEDs
+/// respond linearly to that, but eyes do not, so without a curve dark colors
+/// look far too bright.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ColorCurve {
+    Gamma(f32),
+    /// Lightness to luminance as defined by CIE 1931.
+    Cie1931,
+}
+
+impl ColorCurve {
+    pub fn from_name(name: &str) -> Option<ColorCurve> {
+        if name == "cie1931" {
+            return Some(ColorCurve::Cie1931);
+        }
+        match name.parse::<f32>() {
+            Ok(gamma) if gamma > 0.0 => Some(ColorCurve::Gamma(gamma)),
+            _ => None,
+        }
+    }
+
+    fn luminance(&self, value: f32) -> f32 {
+        match self {
+            ColorCurve::Gamma(gamma) => value.powf(*gamma),
+            ColorCurve::Cie1931 => {
+                let lightness = value * 100.0;
+                if lightness <= 8.0 {
+                    lightness / 902.3
+                } else {
+                    ((lightness + 16.0) / 116.0).powi(3)
+                }
+            }
+        }
+    }
+}
+
+/// Precomputed curves for the red, green and blue channels, from pixel values
+/// of `COLOR_DEPTH` bits to on-times of `output_bits` bits.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColorLut {
+    curves: [ColorCurve; 3],
+    output_bits: usize,
+    brightness: u8,
+    channels: [Vec<u16>; 3],
+}
+
+impl ColorLut {
+    pub fn new(curves: [ColorCurve; 3], output_bits: usize) -> ColorLut {
+        ColorLut::with_brightness(curves, output_bits, 100)
+    }
+
+    /// Scales the luminance, not the pixel values, so all three channels are
+    /// dimmed by the same factor and the hue is kept.
+    pub fn with_brightness(curves: [ColorCurve; 3], output_bits: usize, brightness: u8) -> ColorLut {
+        let brightness = brightness.min(100);
+        let table = |curve: ColorCurve| -> Vec<u16> {
+            let max_input = ((1 << COLOR_DEPTH) - 1) as f32;
+            let max_output = ((1 << output_bits) - 1) as f32 * brightness as f32 / 100.0;
+            (0..1 << COLOR_DEPTH)
+                .map(|value| (curve.luminance(value as f32 / max_input) * max_output).round() as u16)
+                .collect()
+        };
+        ColorLut {
+            curves,
+            output_bits,
+            brightness,
+            channels: [table(curves[0]), table(curves[1]), table(curves[2])],
+        }
+    }
+
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    pub fn dimmed(&self, brightness: u8) -> ColorLut {
+        ColorLut::with_brightness(self.curves, self.output_bits, brightness)
+    }
+
+    pub fn map(&self, pixel: Pixel) -> Pixel {
+        let lookup = |channel: usize, value: u16| -> u16 {
+            let table = &self.channels[channel];
+            table[(value as usize).min(table.len() - 1)]
+        };
+        Pixel::new_colored_pixel(lookup(0, pixel.r), lookup(1, pixel.g), lookup(2, pixel.b))
+    }
+}
+
+impl Default for ColorLut {
+    fn default() -> ColorLut {
+        ColorLut::new([ColorCurve::Cie1931; 3], MAX_PWM_BITS)
+    }
+}
+
+/// Brightness in percent, shared between the refresh loop and whoever wants
+/// to change it while the panel is running.
+#[derive(Clone, Debug)]
+pub struct BrightnessControl {
+    percent: Arc<AtomicUsize>,
+}
+
+impl BrightnessControl {
+    pub fn new(percent: u8) -> BrightnessControl {
+        BrightnessControl {
+            percent: Arc::new(AtomicUsize::new(percent.min(100) as usize)),
+        }
+    }
+
+    pub fn set(&self, percent: u8) {
+        self.percent.store(percent.min(100) as usize, Ordering::SeqCst);
+    }
+
+    pub fn get(&self) -> u8 {
+        self.percent.load(Ordering::SeqCst) as u8
+    }
+}
+
+#[test]
+fn color_lut_identity_test() {
+    let lut = ColorLut::new([ColorCurve::Gamma(1.0); 3], COLOR_DEPTH);
+    for value in 0..256 {
+        assert_eq!(Pixel::new_colored_pixel(value, value, value), lut.map(Pixel::new_colored_pixel(value, value, value)));
+    }
+}
+
+#[test]
+fn color_lut_cie1931_test() {
+    let lut = ColorLut::new([ColorCurve::Cie1931; 3], COLOR_DEPTH);
+    assert_eq!(Pixel::new(), lut.map(Pixel::new()));
+    assert_eq!(Pixel::new_colored_pixel(255, 255, 255), lut.map(Pixel::new_colored_pixel(255, 255, 255)));
+    // Half lightness is under a fifth of the luminance.
+    assert_eq!(47, lut.map(Pixel::new_colored_pixel(128, 0, 0)).r);
+    assert_eq!(1, lut.map(Pixel::new_colored_pixel(10, 0, 0)).r);
+}
+
+#[test]
+fn color_lut_per_channel_gamma_test() {
+    let lut = ColorLut::new([ColorCurve::Gamma(1.0), ColorCurve::Gamma(2.0), ColorCurve::Gamma(2.2)], COLOR_DEPTH);
+    let pixel = lut.map(Pixel::new_colored_pixel(128, 128, 128));
+    assert_eq!(Pixel::new_colored_pixel(128, 64, 56), pixel);
+
+    let wide = ColorLut::new([ColorCurve::Gamma(1.0); 3], MAX_PWM_BITS);
+    assert_eq!(2047, wide.map(Pixel::new_colored_pixel(255, 0, 0)).r);
+}
+
+#[test]
+fn color_curve_from_name_test() {
+    assert_eq!(Some(ColorCurve::Cie1931), ColorCurve::from_name("cie1931"));
+    assert_eq!(Some(ColorCurve::Gamma(2.2)), ColorCurve::from_name("2.2"));
+    assert_eq!(None, ColorCurve::from_name("0"));
+    assert_eq!(None, ColorCurve::from_name("bright"));
+}
+
+#[test]
+fn color_lut_brightness_keeps_hue_test() {
+    let lut = ColorLut::new([ColorCurve::Gamma(1.0); 3], 11).dimmed(25);
+    assert_eq!(25, lut.brightness());
+    // Red and green keep their 2:1 ratio.
+    let pixel = lut.map(Pixel::new_colored_pixel(255, 128, 0));
+    assert_eq!(Pixel::new_colored_pixel(512, 257, 0), pixel);
+    assert_eq!(0, lut.dimmed(0).map(Pixel::new_colored_pixel(255, 255, 255)).r);
+}
+
+#[test]
+fn brightness_control_is_shared_test() {
+    let control = BrightnessControl::new(100);
+    let other = control.clone();
+    std::thread::spawn(move || other.set(150)).join().unwrap();
+    assert_eq!(100, control.get());
+    control.set(40);
+    assert_eq!(40, control.get());
+}
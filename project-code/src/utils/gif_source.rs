@@ -0,0 +1,105 @@
+use std::io::Read;
+use std::time::Duration;
+
+use gif;
+use gif::{DisposalMethod, SetParameter};
+
+use super::animation::{Compositor, Disposal, FramePatch};
+use super::image::Image;
+use super::image_source::{ImageFormat, ImageSource, SourceError};
+use super::pixel::Pixel;
+
+/// Yields every frame of a (possibly animated) GIF, composited according to
+/// the transparency and disposal method of the frames before it.
+pub struct GifSource<R: Read> {
+    reader: gif::Reader<R>,
+    compositor: Compositor,
+    delay: Option<Duration>,
+}
+
+impl<R: Read> GifSource<R> {
+    pub fn new(reader: R) -> Result<GifSource<R>, SourceError> {
+        let mut decoder = gif::Decoder::new(reader);
+        decoder.set(gif::ColorOutput::RGBA);
+        let reader = decoder.read_info().map_err(decode_error)?;
+        let compositor = Compositor::new(reader.width() as usize, reader.height() as usize);
+
+        Ok(GifSource {
+            reader,
+            compositor,
+            delay: None,
+        })
+    }
+}
+
+impl<R: Read> ImageSource for GifSource<R> {
+    fn next_image(&mut self) -> Result<Option<Image>, SourceError> {
+        let frame = match self.reader.read_next_frame().map_err(decode_error)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+
+        let patch = FramePatch {
+            left: frame.left as usize,
+            top: frame.top as usize,
+            width: frame.width as usize,
+            height: frame.height as usize,
+            pixels: frame.buffer.chunks(4)
+                .map(|rgba| if rgba[3] == 0 {
+                    None
+                } else {
+                    Some(Pixel::new_colored_pixel(rgba[0] as u16, rgba[1] as u16, rgba[2] as u16))
+                })
+                .collect(),
+            disposal: match frame.dispose {
+                DisposalMethod::Background => Disposal::Background,
+                DisposalMethod::Previous => Disposal::Previous,
+                _ => Disposal::Keep,
+            },
+        };
+        // GIF delays are in hundredths of a second.
+        self.delay = Some(Duration::from_millis(frame.delay as u64 * 10));
+
+        Ok(Some(self.compositor.draw(&patch)))
+    }
+
+    fn frame_delay(&self) -> Option<Duration> {
+        self.delay
+    }
+}
+
+fn decode_error(why: gif::DecodingError) -> SourceError {
+    SourceError::Decode(ImageFormat::Gif, why.to_string())
+}
+
+#[test]
+fn gif_source_composites_frames_test() {
+    use std::fs::File;
+
+    let mut source = GifSource::new(File::open("resources/image_source/animation.gif").unwrap()).unwrap();
+    let first = source.next_image().unwrap().unwrap();
+    assert_eq!(Some(Duration::from_millis(100)), source.frame_delay());
+    let second = source.next_image().unwrap().unwrap();
+
+    assert_eq!(Pixel::new_colored_pixel(255, 0, 0), first.pixels[0][0]);
+    assert_eq!(Pixel::new_colored_pixel(255, 0, 0), first.pixels[1][1]);
+    // The second frame only covers the bottom right pixel.
+    assert_eq!(Pixel::new_colored_pixel(255, 0, 0), second.pixels[0][0]);
+    assert_eq!(Pixel::new_colored_pixel(0, 0, 255), second.pixels[1][1]);
+    assert!(source.next_image().unwrap().is_none());
+}
+
+#[test]
+fn gif_source_disposal_test() {
+    use std::fs::File;
+
+    let mut source = GifSource::new(File::open("resources/image_source/disposal.gif").unwrap()).unwrap();
+    let images: Vec<Image> = (0..3).map(|_frame| source.next_image().unwrap().unwrap()).collect();
+
+    // Frame two draws blue on the left and is restored, frame three clears
+    // nothing and draws blue on the right.
+    assert_eq!(vec![Pixel::new_colored_pixel(255, 0, 0), Pixel::new_colored_pixel(255, 0, 0)], images[0].pixels[0]);
+    assert_eq!(vec![Pixel::new_colored_pixel(0, 0, 255), Pixel::new_colored_pixel(255, 0, 0)], images[1].pixels[0]);
+    assert_eq!(vec![Pixel::new_colored_pixel(255, 0, 0), Pixel::new_colored_pixel(0, 0, 255)], images[2].pixels[0]);
+    assert_eq!(Some(Duration::from_millis(30)), source.frame_delay());
+}
@@ -1,82 +1,123 @@
 //necessary for running on RaspPi
 use std;
 use std::error::Error;
-use std::fs::OpenOptions;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::ErrorKind;
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::AsRawFd;
-use std::sync::Arc;
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering;
+use std::path::Path;
 
 use libc;
 use mmap::{MapOption, MemoryMap};
-use time;
 
+use super::bitplane_buffer::BitplaneBuffer;
 use super::frame::Frame;
-use super::image::Image;
+use super::gamma::{BrightnessControl, ColorLut};
+use super::panel_config::PanelConfig;
+use super::pin_mapping::PinMapping;
+use super::register_block::{MmapRegisterBlock, RegisterBlock};
+use super::time::{elapsed_nanos, Clock, Timer};
+#[cfg(test)]
+use std::rc::Rc;
+#[cfg(test)]
 use super::pixel::Pixel;
-use super::time::Timer;
-use time::Timespec;
-
-const PIN_OE: u64 = 4;
-const PIN_CLK: u64 = 17;
-const PIN_LAT: u64 = 21;
-const PIN_A: u64 = 22;
-const PIN_B: u64 = 26;
-const PIN_C: u64 = 27;
-const PIN_D: u64 = 20;
-const PIN_E: u64 = 24;
-const PIN_R1: u64 = 5;
-const PIN_G1: u64 = 13;
-const PIN_B1: u64 = 6;
-const PIN_R2: u64 = 12;
-const PIN_G2: u64 = 16;
-const PIN_B2: u64 = 23;
-
-const BCM2709_PERI_BASE: u64 = 0x3F000000;
+#[cfg(test)]
+use super::pixel_mapper::IdentityMapper;
+#[cfg(test)]
+use super::register_block::{FakePanel, FakeRegisterBlock, RegisterWrite};
+#[cfg(test)]
+use super::time::FakeClock;
+
 const GPIO_REGISTER_OFFSET: u64 = 0x200000;
+const GPIOMEM_PATH: &str = "/dev/gpiomem";
+pub const DEVMEM_PATH: &str = "/dev/mem";
 
 pub const REGISTER_BLOCK_SIZE: u64 = 4096;
 pub const COLOR_DEPTH: usize = 8;
-pub const ROWS: usize = 16;
-pub const COLUMNS: usize = 32;
-pub const SUB_PANELS: usize = 2;
+/// On-times are computed with this many bits. Rendering fewer PWM bits drops
+/// the lowest planes.
+pub const MAX_PWM_BITS: usize = 11;
+const DEFAULT_LSB_NANOS: u32 = 1000;
+pub const MAX_SLOWDOWN: u32 = 4;
+// Enough columns for the microsecond counter to resolve the clock to 0.1%.
+const CALIBRATION_CYCLES: u32 = 10 * 1000;
+const CALIBRATION_RUNS: usize = 3;
+
+pub const GPFSEL0: usize = 0;
+pub const GPSET0: usize = 7;
+pub const GPCLR0: usize = 10;
+pub const GPLEV0: usize = 13;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GpioSlowdown {
+    /// Every write to the pins is repeated this many times.
+    Fixed(u32),
+    /// The smallest slowdown that keeps the clock under a maximum frequency.
+    Auto,
+}
 
-macro_rules! GPIO_BIT {
-    ($bit:expr) => {
-        1 << $bit
-    };
+#[derive(Debug)]
+pub enum DriverError {
+    Open { path: &'static str, source: io::Error },
+    Mmap { path: &'static str, offset: usize, reason: String },
+    InvalidConfig(String),
 }
 
-const VALID_BITS: u64 = GPIO_BIT!(PIN_OE) | GPIO_BIT!(PIN_CLK) | GPIO_BIT!(PIN_LAT) |
-    GPIO_BIT!(PIN_A) | GPIO_BIT!(PIN_B) | GPIO_BIT!(PIN_C) | GPIO_BIT!(PIN_D) | GPIO_BIT!(PIN_E) |
-    GPIO_BIT!(PIN_R1) | GPIO_BIT!(PIN_G1) | GPIO_BIT!(PIN_B1) |
-    GPIO_BIT!(PIN_R2) | GPIO_BIT!(PIN_G2) | GPIO_BIT!(PIN_B2);
+impl fmt::Display for DriverError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DriverError::Open { path, source } => write!(f, "{}", describe_open_error(path, source)),
+            DriverError::Mmap { path, offset, reason } => write!(f, "Could not mmap {} at offset 0x{:X}: {}", path, offset, reason),
+            DriverError::InvalidConfig(reason) => write!(f, "Invalid configuration: {}", reason),
+        }
+    }
+}
 
-pub struct GPIO {
-    gpio_map_: Option<MemoryMap>,
+impl Error for DriverError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DriverError::Open { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+pub struct GPIO<R: RegisterBlock = MmapRegisterBlock> {
+    registers: R,
+    config: PanelConfig,
+    mapping: PinMapping,
     output_bits_: u32,
     input_bits_: u32,
     slowdown_: u32,
-    gpio_port_: *mut u32,
-    gpio_set_bits_: *mut u32,
-    gpio_clr_bits_: *mut u32,
-    gpio_read_bits_: *mut u32,
     pub row_mask: u32,
-    bitplane_timings: [u32; COLOR_DEPTH],
+    pwm_bits: usize,
+    bitplane_timings: [u32; MAX_PWM_BITS],
+    color_lut: ColorLut,
+    brightness: BrightnessControl,
+    bitplanes: BitplaneBuffer,
 }
 
 impl GPIO {
-    fn configure_output_pin(self: &mut GPIO, pin_num: u64) {
-        let register_num = (pin_num / 10) as isize;
-        let register_ref = unsafe { self.gpio_port_.offset(register_num) };
-        let current_val = unsafe { std::ptr::read_volatile(register_ref) };
+    /// Maps the GPIO registers through /dev/gpiomem, or through /dev/mem at
+    /// `peripheral_base` where there is no /dev/gpiomem.
+    pub fn new(slowdown: u32, config: &PanelConfig, mapping: &PinMapping, peripheral_base: u64) -> Result<GPIO, DriverError> {
+        let registers = MmapRegisterBlock::gpio(peripheral_base)?;
+        GPIO::with_registers(registers, slowdown, config, mapping)
+    }
+}
+
+impl<R: RegisterBlock> GPIO<R> {
+    fn configure_output_pin(self: &mut GPIO<R>, pin_num: u64) {
+        let register_num = GPFSEL0 + (pin_num / 10) as usize;
+        let current_val = self.registers.read(register_num);
         let new_val = (current_val & !(7 << ((pin_num % 10) * 3))) | (1 << ((pin_num % 10) * 3));
-        unsafe { std::ptr::write_volatile(register_ref, new_val) };
+        self.registers.write(register_num, new_val);
     }
 
-    fn init_outputs(self: &mut GPIO, mut outputs: u32) -> u32 {
-        outputs &= VALID_BITS as u32;
+    fn init_outputs(self: &mut GPIO<R>, mut outputs: u32) -> u32 {
+        outputs &= self.mapping.valid_bits();
         outputs &= !(self.output_bits_ | self.input_bits_);
 
         for b in 0..28 {
@@ -89,149 +130,243 @@ impl GPIO {
         outputs
     }
 
-    fn set_bits(self: &mut GPIO, value: u32) {
-        unsafe {
-            std::ptr::write_volatile(self.gpio_set_bits_, value);
-            for _iter in 0..self.slowdown_ {
-                std::ptr::write_volatile(self.gpio_set_bits_, value);
-            }
+    fn set_bits(self: &mut GPIO<R>, value: u32) {
+        self.registers.write(GPSET0, value);
+        for _iter in 0..self.slowdown_ {
+            self.registers.write(GPSET0, value);
         }
     }
 
-    fn clear_bits(self: &mut GPIO, value: u32) {
-        unsafe {
-            std::ptr::write_volatile(self.gpio_clr_bits_, value);
-            for _iter in 0..self.slowdown_ {
-                std::ptr::write_volatile(self.gpio_clr_bits_, value);
-            }
+    fn clear_bits(self: &mut GPIO<R>, value: u32) {
+        self.registers.write(GPCLR0, value);
+        for _iter in 0..self.slowdown_ {
+            self.registers.write(GPCLR0, value);
         }
     }
 
-    fn write_masked_bits(self: &mut GPIO, value: u32, mask: u32) {
+    fn write_masked_bits(self: &mut GPIO<R>, value: u32, mask: u32) {
         self.clear_bits(!value & mask);
         self.set_bits(value & mask);
     }
 
-    pub fn new(slowdown: u32) -> GPIO {
-        let map = mmap_bcm_register(GPIO_REGISTER_OFFSET as usize);
-
-        if map.is_none() {
-            eprint!("map error");
+    pub fn with_registers(registers: R, slowdown: u32, config: &PanelConfig, mapping: &PinMapping) -> Result<GPIO<R>, DriverError> {
+        if config.sub_panels() != 2 {
+            return Err(DriverError::InvalidConfig(String::from("Only panels with two sub panels (R1/R2 and G1/G2 and B1/B2) are supported")));
         }
-
-        let mut io: GPIO = GPIO {
-            gpio_map_: None,
+        mapping.validate()
+            .and_then(|_| mapping.check_panel(config))
+            .map_err(DriverError::InvalidConfig)?;
+
+        let mut io: GPIO<R> = GPIO {
+            registers,
+            config: *config,
+            mapping: mapping.clone(),
             output_bits_: 0,
             input_bits_: 0,
             slowdown_: slowdown,
-            gpio_port_: 0 as *mut u32,
-            gpio_set_bits_: 0 as *mut u32,
-            gpio_clr_bits_: 0 as *mut u32,
-            gpio_read_bits_: 0 as *mut u32,
             row_mask: 0,
-            bitplane_timings: [0; COLOR_DEPTH],
+            pwm_bits: COLOR_DEPTH,
+            bitplane_timings: [0; MAX_PWM_BITS],
+            color_lut: ColorLut::default(),
+            brightness: BrightnessControl::new(100),
+            bitplanes: BitplaneBuffer::new(config),
         };
 
-        match &map {
-            &Some(ref m) => {
-                unsafe {
-                    io.gpio_port_ = m.data() as *mut u32;
-                    io.gpio_set_bits_ = io.gpio_port_.offset(7);
-                    io.gpio_clr_bits_ = io.gpio_port_.offset(10);
-                    io.gpio_read_bits_ = io.gpio_port_.offset(13);
-                }
-                let mut all_used_bits: u32 = 0;
-                all_used_bits |= GPIO_BIT!(PIN_OE) | GPIO_BIT!(PIN_CLK) | GPIO_BIT!(PIN_LAT) |
-                    GPIO_BIT!(PIN_R1) | GPIO_BIT!(PIN_G1) | GPIO_BIT!(PIN_B1) |
-                    GPIO_BIT!(PIN_R2) | GPIO_BIT!(PIN_G2) | GPIO_BIT!(PIN_B2);
-
-                set_row_mask(&mut io);
-
-                all_used_bits |= io.row_mask;
-                let result = io.init_outputs(all_used_bits);
-                assert_eq!(result, all_used_bits);
-
-                let mut timing_ns: u32 = 1000;
-                for b in 0..COLOR_DEPTH {
-                    io.bitplane_timings[b] = timing_ns;
-                    timing_ns *= 2;
-                }
+        let mut all_used_bits: u32 = 0;
+        all_used_bits |= GPIO_BIT!(mapping.oe) | GPIO_BIT!(mapping.clk) | GPIO_BIT!(mapping.lat) |
+            mapping.color_bits(config.parallel);
+
+        set_row_mask(&mut io);
+
+        all_used_bits |= io.row_mask;
+        let outputs = io.init_outputs(all_used_bits);
+        if outputs != all_used_bits {
+            return Err(DriverError::InvalidConfig(format!("Could not configure GPIO pins 0x{:08X} as outputs", all_used_bits & !outputs)));
+        }
+
+        io.set_bitplane_timings(DEFAULT_LSB_NANOS);
+        Ok(io)
+    }
+
+    /// Fewer bits give a faster refresh rate at the cost of color depth.
+    pub fn set_pwm_bits(&mut self, pwm_bits: usize) -> Result<(), DriverError> {
+        if !(1..=MAX_PWM_BITS).contains(&pwm_bits) {
+            return Err(DriverError::InvalidConfig(format!("PWM bits must be between 1 and {} (got {})", MAX_PWM_BITS, pwm_bits)));
+        }
+        let lsb_nanos = self.bitplane_timings[self.first_plane()];
+        check_pwm_timing(pwm_bits, lsb_nanos)?;
+        self.pwm_bits = pwm_bits;
+        self.set_bitplane_timings(lsb_nanos);
+        Ok(())
+    }
+
+    /// Sets how long the least significant rendered plane is shown, every
+    /// next plane is shown twice as long.
+    pub fn set_pwm_lsb_nanos(&mut self, lsb_nanos: u32) -> Result<(), DriverError> {
+        check_pwm_timing(self.pwm_bits, lsb_nanos)?;
+        self.set_bitplane_timings(lsb_nanos);
+        Ok(())
+    }
+
+    fn set_bitplane_timings(&mut self, lsb_nanos: u32) {
+        let first_plane = self.first_plane();
+        for plane in 0..MAX_PWM_BITS {
+            self.bitplane_timings[plane] = if plane < first_plane { 0 } else { lsb_nanos << (plane - first_plane) };
+        }
+    }
+
+    fn first_plane(&self) -> usize {
+        MAX_PWM_BITS - self.pwm_bits
+    }
+
+    /// Clocks in `cycles` columns the way `send_values` does, with the output
+    /// disabled and without latching them, and returns the clock frequency
+    /// that was reached in Hz.
+    pub fn measure_clock_hz<T: Clock>(&mut self, timer: &Timer<T>, cycles: u32) -> u64 {
+        let color_clock_mask = self.mapping.color_bits(self.config.parallel) | GPIO_BIT!(self.mapping.clk);
+        self.set_bits(GPIO_BIT!(self.mapping.oe));
+
+        let start = timer.clock().now_micros();
+        for _cycle in 0..cycles {
+            self.clear_bits(color_clock_mask);
+            self.write_masked_bits(0, color_clock_mask);
+            self.set_bits(GPIO_BIT!(self.mapping.clk));
+        }
+        let nanos = elapsed_nanos(start, timer.clock().now_micros()).max(1);
+
+        self.clear_bits(color_clock_mask);
+        cycles as u64 * 1_000_000_000 / nanos
+    }
+
+    /// Sets the smallest slowdown for which the clock stays at or below
+    /// `max_clock_hz`, or `MAX_SLOWDOWN` if none does. Returns the slowdown
+    /// and the clock frequency it gives.
+    pub fn calibrate_slowdown<T: Clock>(&mut self, timer: &Timer<T>, max_clock_hz: u64) -> (u32, u64) {
+        let mut clock_hz = 0;
+        for slowdown in 0..=MAX_SLOWDOWN {
+            self.slowdown_ = slowdown;
+            // Being preempted only makes a run slower: the fastest one is
+            // what the panel has to keep up with.
+            clock_hz = (0..CALIBRATION_RUNS).map(|_run| self.measure_clock_hz(timer, CALIBRATION_CYCLES)).max().unwrap_or(0);
+            if clock_hz <= max_clock_hz {
+                break;
             }
-            &None => {}
         }
-        io.gpio_map_ = map;
-        io
+        (self.slowdown_, clock_hz)
     }
 
-    pub fn render_image_frame(&mut self, interrupt_received: Arc<AtomicBool>, image: &Image, frame: &mut Frame, timer: &Timer, scrolling: bool) {
-        frame.next_image_frame(&image);
-        let mut prev_frame_time = time::get_time();
-        let mut current_time: Timespec;
+    /// Takes effect from the next `load_frame`.
+    pub fn set_color_lut(&mut self, color_lut: ColorLut) {
+        self.color_lut = color_lut.dimmed(self.brightness.get());
+    }
 
-        while interrupt_received.load(Ordering::SeqCst) == false {
-            for row_counter in 0..ROWS / 2 {
-                for bitplane_counter in 0..COLOR_DEPTH {
-                    self.send_values(&timer, &frame, row_counter, bitplane_counter);
-                };
-            };
+    /// Takes effect from the next refreshed frame, also while one of the
+    /// render loops is running. Use `brightness_control` to change it from
+    /// another thread.
+    pub fn set_brightness(&self, percent: u8) {
+        self.brightness.set(percent);
+    }
+
+    pub fn brightness_control(&self) -> BrightnessControl {
+        self.brightness.clone()
+    }
+
+    // Whether another thread changed the brightness since the loaded frame
+    // was converted.
+    fn brightness_changed(&self) -> bool {
+        self.brightness.get() != self.color_lut.brightness()
+    }
+
+    fn update_brightness(&mut self) {
+        if self.brightness_changed() {
+            self.color_lut = self.color_lut.dimmed(self.brightness.get());
+        }
+    }
 
-            current_time = time::get_time();
-            let difference = current_time - prev_frame_time;
-            if scrolling && difference >= time::Duration::milliseconds(10) {
-                frame.next_image_frame(&image);
-                prev_frame_time = current_time;
+    /// Converts the frame into the bitplanes `refresh` sends. Call it after
+    /// every change to the frame.
+    pub fn load_frame(&mut self, frame: &Frame) {
+        self.update_brightness();
+        self.bitplanes.fill(frame, &self.config, &self.mapping, &self.color_lut);
+    }
+
+    /// Sends the loaded frame to the panel once.
+    pub fn refresh<T: Clock>(&mut self, timer: &Timer<T>) {
+        for row_counter in 0..self.config.scan_rate {
+            for bitplane_counter in self.first_plane()..MAX_PWM_BITS {
+                self.send_values(timer, row_counter, bitplane_counter);
             };
         };
-        if interrupt_received.load(Ordering::SeqCst) == true {
-            println!("Received CTRL-C");
+    }
+
+    /// Refreshes the loaded frame, converting `frame` again first if the
+    /// brightness was changed from another thread.
+    pub fn refresh_loaded<T: Clock>(&mut self, frame: &Frame, timer: &Timer<T>) {
+        if self.brightness_changed() {
+            self.render_frame(frame, timer);
         } else {
-            println!("Timeout reached");
-        };
-        self.set_bits(GPIO_BIT!(PIN_OE));
+            self.refresh(timer);
+        }
     }
 
-    pub fn render_frame(&mut self, frame: &mut Frame, timer: &Timer) {
-            for row_counter in 0..ROWS / 2 {
-                for bitplane_counter in 0..COLOR_DEPTH {
-                    self.send_values(&timer, &frame, row_counter, bitplane_counter);
-                };
-            };
+    fn render_frame<T: Clock>(&mut self, frame: &Frame, timer: &Timer<T>) {
+        self.load_frame(frame);
+        self.refresh(timer);
     }
 
+    /// Switches all LEDs off until the next refresh.
+    pub fn disable_output(&mut self) {
+        self.set_bits(GPIO_BIT!(self.mapping.oe));
+    }
 
-    fn send_values(&mut self, timer: &Timer, frame: &Frame, row: usize, bitplane_counter: usize) {
+    fn send_values<T: Clock>(&mut self, timer: &Timer<T>, row: usize, bitplane_counter: usize) {
         let row_mask = self.row_mask;
-        let color_clock_mask = GPIO_BIT!(PIN_R1) | GPIO_BIT!(PIN_G1) | GPIO_BIT!(PIN_B1) | GPIO_BIT!(PIN_R2) | GPIO_BIT!(PIN_G2) | GPIO_BIT!(PIN_B2) | GPIO_BIT!(PIN_CLK);
+        let color_clock_mask = self.mapping.color_bits(self.config.parallel) | GPIO_BIT!(self.mapping.clk);
 
-        for c in 0..32 {
+        for c in 0..self.config.canvas_width() {
             self.clear_bits(color_clock_mask);
-            let pixel_top = frame.pixels[row][c];
-            let pixel_bot = frame.pixels[ROWS / 2 + row][c];
-            let plane_bits = get_plane_bits(pixel_top, pixel_bot, bitplane_counter);
+            let plane_bits = self.bitplanes.plane(row, bitplane_counter)[c];
 
             self.write_masked_bits(plane_bits, color_clock_mask);
-            self.set_bits(GPIO_BIT!(PIN_CLK));
+            self.set_bits(GPIO_BIT!(self.mapping.clk));
         };
 
-        self.clear_bits(GPIO_BIT!(PIN_R1) | GPIO_BIT!(PIN_G1) | GPIO_BIT!(PIN_B1) | GPIO_BIT!(PIN_R2) | GPIO_BIT!(PIN_G2) | GPIO_BIT!(PIN_B2) | GPIO_BIT!(PIN_CLK));
-        self.write_masked_bits(get_row_bits(row), row_mask);
+        self.clear_bits(color_clock_mask);
+        let row_bits = get_row_bits(row, &self.mapping);
+        self.write_masked_bits(row_bits, row_mask);
 
-        self.set_bits(GPIO_BIT!(PIN_LAT));
-        self.clear_bits(GPIO_BIT!(PIN_LAT));
-        self.clear_bits(GPIO_BIT!(PIN_OE));
-        timer.nanosleep(self.bitplane_timings[bitplane_counter] as u32);
-        self.set_bits(GPIO_BIT!(PIN_OE));
+        self.set_bits(GPIO_BIT!(self.mapping.lat));
+        self.clear_bits(GPIO_BIT!(self.mapping.lat));
+        self.clear_bits(GPIO_BIT!(self.mapping.oe));
+        timer.nanosleep(self.bitplane_timings[bitplane_counter]);
+        self.set_bits(GPIO_BIT!(self.mapping.oe));
     }
 }
 
-pub fn mmap_bcm_register(register_offset: usize) -> Option<MemoryMap> {
+// The most significant plane must still fit in a u32.
+fn check_pwm_timing(pwm_bits: usize, lsb_nanos: u32) -> Result<(), DriverError> {
+    if lsb_nanos == 0 || (lsb_nanos.leading_zeros() as usize) < pwm_bits - 1 {
+        return Err(DriverError::InvalidConfig(format!("PWM LSB duration of {}ns is out of range for {} PWM bits", lsb_nanos, pwm_bits)));
+    }
+    Ok(())
+}
+
+pub fn mmap_gpio_register(peripheral_base: u64) -> Result<MemoryMap, DriverError> {
+    if Path::new(GPIOMEM_PATH).exists() {
+        mmap_register_file(GPIOMEM_PATH, 0)
+    } else {
+        mmap_bcm_register(peripheral_base, GPIO_REGISTER_OFFSET as usize)
+    }
+}
+
+pub fn mmap_bcm_register(peripheral_base: u64, register_offset: usize) -> Result<MemoryMap, DriverError> {
+    mmap_register_file(DEVMEM_PATH, peripheral_base as usize + register_offset)
+}
+
+fn mmap_register_file(path: &'static str, offset: usize) -> Result<MemoryMap, DriverError> {
     let mem_file =
-        match OpenOptions::new()
-            .read(true)
-            .write(true)
-            .custom_flags(libc::O_SYNC)
-            .open("/dev/mem") {
-            Err(why) => panic!("couldn't open /dev/mem: {}", why.description()),
+        match open_register_file(path) {
+            Err(why) => return Err(DriverError::Open { path, source: why }),
             Ok(file) => file
         };
 
@@ -240,7 +375,7 @@ pub fn mmap_bcm_register(register_offset: usize) -> Option<MemoryMap> {
         MapOption::MapReadable,
         MapOption::MapWritable,
         MapOption::MapFd(mem_file.as_raw_fd()),
-        MapOption::MapOffset(BCM2709_PERI_BASE as usize + register_offset as usize)
+        MapOption::MapOffset(offset)
     ];
 
     let result = match MemoryMap::new(REGISTER_BLOCK_SIZE as usize, mmap_options)
@@ -248,82 +383,348 @@ pub fn mmap_bcm_register(register_offset: usize) -> Option<MemoryMap> {
             Ok(mmap) => {
                 mmap
             }
-            Err(err) => panic!("Could not read the mmap: {}", err),
+            Err(err) => return Err(DriverError::Mmap { path, offset, reason: err.to_string() }),
         };
 
 
     return match result.data().is_null() {
-        true => {
-            eprintln!("mmap error: {}", std::io::Error::last_os_error());
-            eprintln!("Pi3: MMapping from base 0x{:X}, offset 0x{:X}", BCM2709_PERI_BASE, register_offset);
-            None
-        }
-        false => Some(result)
+        true => Err(DriverError::Mmap { path, offset, reason: io::Error::last_os_error().to_string() }),
+        false => Ok(result)
     };
 }
 
-fn get_plane_bits(top: Pixel, bot: Pixel, plane: usize) -> u32 {
-    let mut out: u32 = 0;
-    if top.r & (1 << plane) != 0 {
-        out |= GPIO_BIT!(PIN_R1);
-    }
-    if bot.r & (1 << plane) != 0 {
-        out |= GPIO_BIT!(PIN_R2);
-    }
-    if top.b & (1 << plane) != 0 {
-        out |= GPIO_BIT!(PIN_B1);
-    }
-    if bot.b & (1 << plane) != 0 {
-        out |= GPIO_BIT!(PIN_B2);
-    }
-    if top.g & (1 << plane) != 0 {
-        out |= GPIO_BIT!(PIN_G1);
-    }
-    if bot.g & (1 << plane) != 0 {
-        out |= GPIO_BIT!(PIN_G2);
+pub fn open_register_file(path: &str) -> std::io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_SYNC)
+        .open(path)
+}
+
+pub fn describe_open_error(path: &str, why: &io::Error) -> String {
+    match why.kind() {
+        ErrorKind::PermissionDenied if path == GPIOMEM_PATH =>
+            format!("Permission denied opening {}: add this user to the 'gpio' group, or run as root", path),
+        ErrorKind::PermissionDenied =>
+            format!("Permission denied opening {}: this needs root or the CAP_SYS_RAWIO capability", path),
+        ErrorKind::NotFound =>
+            format!("{} does not exist: is this a Raspberry Pi?", path),
+        _ => format!("Could not open {}: {}", path, why),
     }
-    out
 }
 
-fn get_row_bits(double_row: usize) -> u32 {
+fn get_row_bits(double_row: usize, mapping: &PinMapping) -> u32 {
     let mut pin = 0;
     if double_row & 0x01 != 0 {
-        pin |= GPIO_BIT!(PIN_A);
+        pin |= GPIO_BIT!(mapping.a);
     }
     if double_row & 0x02 != 0 {
-        pin |= GPIO_BIT!(PIN_B);
+        pin |= GPIO_BIT!(mapping.b);
     }
     if double_row & 0x04 != 0 {
-        pin |= GPIO_BIT!(PIN_C);
+        pin |= GPIO_BIT!(mapping.c);
+    }
+    if double_row & 0x08 != 0 {
+        pin |= GPIO_BIT!(mapping.d);
+    }
+    if let Some(e) = mapping.e {
+        if double_row & 0x10 != 0 {
+            pin |= GPIO_BIT!(e);
+        }
     }
     pin
 }
 
-fn set_row_mask(gpio: &mut GPIO) {
-    gpio.row_mask = GPIO_BIT!(PIN_A);
-    let rows_count = ROWS / SUB_PANELS;
+fn set_row_mask<R: RegisterBlock>(gpio: &mut GPIO<R>) {
+    gpio.row_mask = GPIO_BIT!(gpio.mapping.a);
+    let rows_count = gpio.config.scan_rate;
     if rows_count > 2 {
-        gpio.row_mask |= GPIO_BIT!(PIN_B);
+        gpio.row_mask |= GPIO_BIT!(gpio.mapping.b);
     }
     if rows_count > 4 {
-        gpio.row_mask |= GPIO_BIT!(PIN_C);
+        gpio.row_mask |= GPIO_BIT!(gpio.mapping.c);
     }
     if rows_count > 8 {
-        gpio.row_mask |= GPIO_BIT!(PIN_D);
+        gpio.row_mask |= GPIO_BIT!(gpio.mapping.d);
     }
-    if rows_count > 16 {
-        gpio.row_mask |= GPIO_BIT!(PIN_E);
+    if let Some(e) = gpio.mapping.e {
+        if rows_count > 16 {
+            gpio.row_mask |= GPIO_BIT!(e);
+        }
     }
 }
 
 #[test]
 fn get_row_bits_test() {
-    assert_eq!(0, get_row_bits(0), "Invalid row bits");
-    assert_eq!(GPIO_BIT!(PIN_A), get_row_bits(1), "Invalid row bits");
-    assert_eq!(GPIO_BIT!(PIN_B), get_row_bits(2), "Invalid row bits");
-    assert_eq!(GPIO_BIT!(PIN_A) | GPIO_BIT!(PIN_B), get_row_bits(3), "Invalid row bits");
-    assert_eq!(GPIO_BIT!(PIN_C), get_row_bits(4), "Invalid row bits");
-    assert_eq!(GPIO_BIT!(PIN_C) | GPIO_BIT!(PIN_A), get_row_bits(5), "Invalid row bits");
-    assert_eq!(GPIO_BIT!(PIN_C) | GPIO_BIT!(PIN_B), get_row_bits(6), "Invalid row bits");
-    assert_eq!(GPIO_BIT!(PIN_C) | GPIO_BIT!(PIN_B) | GPIO_BIT!(PIN_A), get_row_bits(7), "Invalid row bits");
-}
\ No newline at end of file
+    let pins = PinMapping::adafruit_hat();
+    assert_eq!(0, get_row_bits(0, &pins), "Invalid row bits");
+    assert_eq!(GPIO_BIT!(pins.a), get_row_bits(1, &pins), "Invalid row bits");
+    assert_eq!(GPIO_BIT!(pins.b), get_row_bits(2, &pins), "Invalid row bits");
+    assert_eq!(GPIO_BIT!(pins.a) | GPIO_BIT!(pins.b), get_row_bits(3, &pins), "Invalid row bits");
+    assert_eq!(GPIO_BIT!(pins.c), get_row_bits(4, &pins), "Invalid row bits");
+    assert_eq!(GPIO_BIT!(pins.c) | GPIO_BIT!(pins.a), get_row_bits(5, &pins), "Invalid row bits");
+    assert_eq!(GPIO_BIT!(pins.c) | GPIO_BIT!(pins.b), get_row_bits(6, &pins), "Invalid row bits");
+    assert_eq!(GPIO_BIT!(pins.c) | GPIO_BIT!(pins.b) | GPIO_BIT!(pins.a), get_row_bits(7, &pins), "Invalid row bits");
+    assert_eq!(GPIO_BIT!(pins.d), get_row_bits(8, &pins), "Invalid row bits");
+    assert_eq!(GPIO_BIT!(pins.d) | GPIO_BIT!(pins.a), get_row_bits(9, &pins), "Invalid row bits");
+    assert_eq!(GPIO_BIT!(pins.e.unwrap()), get_row_bits(16, &pins), "Invalid row bits");
+    assert_eq!(GPIO_BIT!(pins.e.unwrap()) | GPIO_BIT!(pins.d) | GPIO_BIT!(pins.c) | GPIO_BIT!(pins.b) | GPIO_BIT!(pins.a), get_row_bits(31, &pins), "Invalid row bits");
+}
+
+#[test]
+fn set_row_mask_test() {
+    let panel = FakePanel::new();
+    let pins = &panel.pins;
+    assert_eq!(GPIO_BIT!(pins.a) | GPIO_BIT!(pins.b) | GPIO_BIT!(pins.c), panel.gpio().row_mask, "Invalid row mask");
+
+    let panel = FakePanel::with_config(PanelConfig::new(64, 32, 16).unwrap(), PinMapping::adafruit_hat());
+    assert_eq!(GPIO_BIT!(pins.a) | GPIO_BIT!(pins.b) | GPIO_BIT!(pins.c) | GPIO_BIT!(pins.d), panel.gpio().row_mask, "Invalid row mask");
+
+    let panel = FakePanel::with_config(PanelConfig::new(64, 64, 32).unwrap(), PinMapping::adafruit_hat());
+    assert_eq!(GPIO_BIT!(pins.a) | GPIO_BIT!(pins.b) | GPIO_BIT!(pins.c) | GPIO_BIT!(pins.d) | GPIO_BIT!(pins.e.unwrap()), panel.gpio().row_mask, "Invalid row mask");
+}
+
+#[test]
+fn init_outputs_configures_used_pins_test() {
+    let panel = FakePanel::new();
+    panel.gpio();
+
+    let (pins, registers) = (&panel.pins, &panel.registers);
+    let chain = pins.chains[0];
+    for pin in &[pins.oe, pins.clk, pins.lat, pins.a, pins.b, pins.c, chain.r1, chain.g1, chain.b1, chain.r2, chain.g2, chain.b2] {
+        let function = (registers.read(GPFSEL0 + (*pin / 10) as usize) >> ((*pin % 10) * 3)) & 7;
+        assert_eq!(1, function, "Pin {} is not configured as output", pin);
+    }
+    assert!(registers.writes().iter().all(|write| write.word < GPSET0), "Only function select words should be written");
+}
+
+#[test]
+fn send_values_pin_sequence_test() {
+    let panel = FakePanel::new();
+    let (pins, config) = (&panel.pins, panel.config);
+    let mut gpio = panel.gpio();
+    let mut frame = Frame::with_mapper(&config, &IdentityMapper).unwrap();
+    frame.pixels[0][0] = Pixel::new_colored_pixel(255, 0, 0);
+    frame.pixels[config.scan_rate][1] = Pixel::new_colored_pixel(0, 0, 255);
+    gpio.load_frame(&frame);
+    panel.registers.clear_writes();
+
+    gpio.send_values(&panel.timer(), 0, 0);
+
+    let color_clock_mask = pins.color_bits(1) | GPIO_BIT!(pins.clk);
+    let writes = panel.registers.writes();
+    assert_eq!(config.width * 4 + 7, writes.len(), "Unexpected number of register writes");
+
+    let first_column = &writes[0..4];
+    assert_eq!(RegisterWrite { word: GPCLR0, value: color_clock_mask }, first_column[0]);
+    assert_eq!(RegisterWrite { word: GPSET0, value: GPIO_BIT!(pins.chains[0].r1) }, first_column[2]);
+    assert_eq!(RegisterWrite { word: GPSET0, value: GPIO_BIT!(pins.clk) }, first_column[3]);
+    assert_eq!(RegisterWrite { word: GPSET0, value: GPIO_BIT!(pins.chains[0].b2) }, writes[6]);
+
+    let tail: Vec<RegisterWrite> = writes[config.width * 4..].to_vec();
+    assert_eq!(vec![
+        RegisterWrite { word: GPCLR0, value: color_clock_mask },
+        RegisterWrite { word: GPCLR0, value: gpio.row_mask },
+        RegisterWrite { word: GPSET0, value: 0 },
+        RegisterWrite { word: GPSET0, value: GPIO_BIT!(pins.lat) },
+        RegisterWrite { word: GPCLR0, value: GPIO_BIT!(pins.lat) },
+        RegisterWrite { word: GPCLR0, value: GPIO_BIT!(pins.oe) },
+    ], tail[..6].to_vec());
+}
+
+#[test]
+fn send_values_chained_panels_test() {
+    let panel = FakePanel::with_config(PanelConfig::default().chained(2, 1).unwrap(), PinMapping::adafruit_hat());
+    let (pins, config) = (&panel.pins, panel.config);
+    let mut gpio = panel.gpio();
+    let mut frame = Frame::with_mapper(&config, &IdentityMapper).unwrap();
+    frame.pixels[config.scan_rate + 1][63] = Pixel::new_colored_pixel(0, 255, 0);
+    gpio.load_frame(&frame);
+    panel.registers.clear_writes();
+
+    gpio.send_values(&panel.timer(), 1, 0);
+
+    let writes = panel.registers.writes();
+    let clocks = writes.iter().filter(|write| write.word == GPSET0 && write.value == GPIO_BIT!(pins.clk)).count();
+    assert_eq!(64, clocks, "Every pixel of the chain should be clocked in");
+    assert_eq!(RegisterWrite { word: GPSET0, value: GPIO_BIT!(pins.chains[0].g2) }, writes[63 * 4 + 2]);
+}
+
+#[test]
+fn send_values_parallel_chains_test() {
+    let panel = FakePanel::with_config(PanelConfig::default().chained(1, 2).unwrap(), PinMapping::regular());
+    let (pins, config) = (&panel.pins, panel.config);
+    let mut gpio = panel.gpio();
+    let mut frame = Frame::with_mapper(&config, &IdentityMapper).unwrap();
+    frame.pixels[0][0] = Pixel::new_colored_pixel(255, 0, 0);
+    frame.pixels[config.height][0] = Pixel::new_colored_pixel(255, 0, 0);
+    gpio.load_frame(&frame);
+    panel.registers.clear_writes();
+
+    gpio.send_values(&panel.timer(), 0, 0);
+
+    let writes = panel.registers.writes();
+    assert_eq!(RegisterWrite { word: GPCLR0, value: pins.color_bits(2) | GPIO_BIT!(pins.clk) }, writes[0]);
+    assert_eq!(RegisterWrite { word: GPSET0, value: GPIO_BIT!(pins.chains[0].r1) | GPIO_BIT!(pins.chains[1].r1) }, writes[2]);
+}
+
+#[test]
+fn describe_open_error_test() {
+    let denied = std::io::Error::from(ErrorKind::PermissionDenied);
+    assert!(describe_open_error(GPIOMEM_PATH, &denied).contains("'gpio' group"));
+    assert!(describe_open_error(DEVMEM_PATH, &denied).contains("CAP_SYS_RAWIO"));
+    assert!(describe_open_error(DEVMEM_PATH, &std::io::Error::from(ErrorKind::NotFound)).contains("does not exist"));
+}
+
+#[test]
+fn with_registers_rejects_invalid_mapping_test() {
+    let mut pins = PinMapping::adafruit_hat();
+    pins.oe = pins.clk;
+    match GPIO::with_registers(FakeRegisterBlock::new(), 0, &PanelConfig::default(), &pins) {
+        Err(DriverError::InvalidConfig(_)) => {},
+        _ => panic!("Expected an invalid configuration error"),
+    }
+}
+
+#[test]
+fn send_values_applies_color_lut_test() {
+    use super::gamma::ColorCurve;
+
+    let panel = FakePanel::new();
+    let mut gpio = panel.gpio();
+    let timer = panel.timer();
+    let mut frame = Frame::with_mapper(&panel.config, &IdentityMapper).unwrap();
+    // Without correction 1 is 8 in 11 bits, with it only 1.
+    frame.pixels[0][0] = Pixel::new_colored_pixel(1, 0, 0);
+
+    gpio.load_frame(&frame);
+    panel.registers.clear_writes();
+    gpio.send_values(&timer, 0, 3);
+    assert_eq!(RegisterWrite { word: GPSET0, value: 0 }, panel.registers.writes()[2]);
+
+    gpio.set_color_lut(ColorLut::new([ColorCurve::Gamma(1.0); 3], MAX_PWM_BITS));
+    gpio.load_frame(&frame);
+    panel.registers.clear_writes();
+    gpio.send_values(&timer, 0, 3);
+    assert_eq!(RegisterWrite { word: GPSET0, value: GPIO_BIT!(panel.pins.chains[0].r1) }, panel.registers.writes()[2]);
+}
+
+#[test]
+fn set_brightness_dims_next_frame_test() {
+    use super::gamma::ColorCurve;
+
+    let panel = FakePanel::new();
+    let mut gpio = panel.gpio();
+    let timer = panel.timer();
+    let mut frame = Frame::with_mapper(&panel.config, &IdentityMapper).unwrap();
+    frame.pixels[0][0] = Pixel::new_colored_pixel(255, 0, 0);
+    gpio.set_color_lut(ColorLut::new([ColorCurve::Gamma(1.0); 3], MAX_PWM_BITS));
+    gpio.load_frame(&frame);
+
+    let control = gpio.brightness_control();
+    control.set(50);
+    gpio.refresh_loaded(&frame, &timer);
+
+    // 255 at half brightness is 1024: only the highest plane is on.
+    let r1_planes: Vec<usize> = (0..MAX_PWM_BITS).filter(|plane| {
+        panel.registers.clear_writes();
+        gpio.send_values(&timer, 0, *plane);
+        panel.registers.writes()[2] == RegisterWrite { word: GPSET0, value: GPIO_BIT!(panel.pins.chains[0].r1) }
+    }).collect();
+    assert_eq!(vec![MAX_PWM_BITS - 1], r1_planes);
+}
+
+#[test]
+fn set_pwm_bits_renders_high_planes_test() {
+    let panel = FakePanel::new();
+    let mut gpio = panel.gpio();
+    assert_eq!([0, 0, 0, 1000, 2000, 4000, 8000, 16000, 32000, 64000, 128000], gpio.bitplane_timings);
+
+    gpio.set_pwm_bits(2).unwrap();
+    gpio.set_pwm_lsb_nanos(200).unwrap();
+    assert_eq!([0, 0, 0, 0, 0, 0, 0, 0, 0, 200, 400], gpio.bitplane_timings);
+
+    let frame = Frame::with_mapper(&panel.config, &IdentityMapper).unwrap();
+    panel.registers.clear_writes();
+    gpio.render_frame(&frame, &panel.timer());
+    let latches = panel.registers.writes().iter().filter(|write| write.word == GPSET0 && write.value == GPIO_BIT!(panel.pins.lat)).count();
+    assert_eq!(panel.config.scan_rate * 2, latches, "Two planes per row should be latched");
+
+    assert!(gpio.set_pwm_bits(0).is_err());
+    assert!(gpio.set_pwm_bits(12).is_err());
+    assert!(gpio.set_pwm_lsb_nanos(0).is_err());
+}
+
+#[test]
+fn set_pwm_bits_keeps_longest_plane_in_range_test() {
+    let panel = FakePanel::new();
+    let mut gpio = panel.gpio();
+    gpio.set_pwm_bits(1).unwrap();
+    gpio.set_pwm_lsb_nanos(1 << 31).unwrap();
+    assert!(gpio.set_pwm_bits(11).is_err());
+    assert_eq!(1 << 31, gpio.bitplane_timings[MAX_PWM_BITS - 1]);
+    assert!(gpio.set_pwm_lsb_nanos(1 << 31).is_ok());
+}
+
+#[test]
+fn refresh_sends_loaded_frame_test() {
+    let panel = FakePanel::new();
+    let mut gpio = panel.gpio();
+    let timer = panel.timer();
+    let mut frame = Frame::with_mapper(&panel.config, &IdentityMapper).unwrap();
+    frame.pixels[0][0] = Pixel::new_colored_pixel(255, 0, 0);
+    gpio.load_frame(&frame);
+    frame.pixels[0][0] = Pixel::new();
+
+    // Changes to the frame only show up once it is loaded again.
+    let r1_set = |gpio: &mut GPIO<FakeRegisterBlock>| {
+        panel.registers.clear_writes();
+        gpio.refresh(&timer);
+        panel.registers.writes().contains(&RegisterWrite { word: GPSET0, value: GPIO_BIT!(panel.pins.chains[0].r1) })
+    };
+    assert!(r1_set(&mut gpio));
+    gpio.load_frame(&frame);
+    assert!(!r1_set(&mut gpio));
+}
+
+// Every write to the pins takes the same time, as on a real Pi.
+#[cfg(test)]
+struct SlowRegisterBlock {
+    clock: Rc<FakeClock>,
+    write_nanos: u32,
+}
+
+#[cfg(test)]
+impl RegisterBlock for SlowRegisterBlock {
+    fn read(&self, _word: usize) -> u32 {
+        0
+    }
+
+    fn write(&mut self, _word: usize, _value: u32) {
+        self.clock.sleep(self.write_nanos);
+    }
+}
+
+#[test]
+fn calibrate_slowdown_test() {
+    let pins = PinMapping::adafruit_hat();
+    let config = PanelConfig::default();
+    let clock = Rc::new(FakeClock::new(0));
+    let registers = SlowRegisterBlock { clock: Rc::clone(&clock), write_nanos: 50 };
+    let mut gpio = GPIO::with_registers(registers, 0, &config, &pins).unwrap();
+    let timer = Timer::with_clock(clock);
+
+    // A column takes four writes: 5MHz without slowdown, 2.5MHz with one
+    // repeat and 1.67MHz with two.
+    let clock_hz = gpio.measure_clock_hz(&timer, 10 * 1000);
+    assert!(clock_hz > 4_990_000 && clock_hz <= 5_000_000, "Clock was {}Hz", clock_hz);
+    let (slowdown, clock_hz) = gpio.calibrate_slowdown(&timer, 2_000_000);
+    assert_eq!(2, slowdown);
+    assert!(clock_hz > 1_600_000 && clock_hz <= 1_666_667, "Clock was {}Hz", clock_hz);
+    // The slowdown stays in use after calibrating.
+    assert_eq!(clock_hz, gpio.measure_clock_hz(&timer, CALIBRATION_CYCLES));
+
+    let (slowdown, clock_hz) = gpio.calibrate_slowdown(&timer, 100_000);
+    assert_eq!(MAX_SLOWDOWN, slowdown);
+    assert!(clock_hz > 100_000);
+}
@@ -0,0 +1,233 @@
+use std::io;
+use std::io::Write;
+
+use super::gpio_driver::{GPCLR0, GPLEV0, GPSET0};
+use super::pin_mapping::PinMapping;
+use super::register_block::RegisterBlock;
+use super::time::{Clock, MonotonicClock};
+#[cfg(test)]
+use std::rc::Rc;
+#[cfg(test)]
+use super::frame::Frame;
+#[cfg(test)]
+use super::gpio_driver::GPIO;
+#[cfg(test)]
+use super::panel_config::PanelConfig;
+#[cfg(test)]
+use super::pixel::Pixel;
+#[cfg(test)]
+use super::pixel_mapper::IdentityMapper;
+#[cfg(test)]
+use super::register_block::FakeRegisterBlock;
+#[cfg(test)]
+use super::time::{FakeClock, Timer};
+
+struct Signal {
+    id: char,
+    pin: u64,
+}
+
+/// Writes the traced pins as a Value Change Dump with nanosecond timestamps.
+struct VcdWriter<W: Write> {
+    out: W,
+    signals: Vec<Signal>,
+    last_nanos: u64,
+}
+
+impl<W: Write> VcdWriter<W> {
+    fn new(mut out: W, mapping: &PinMapping, parallel: usize, levels: u32) -> io::Result<VcdWriter<W>> {
+        let mut signals = Vec::new();
+        writeln!(out, "$version project-code GPIO trace $end")?;
+        writeln!(out, "$timescale 1ns $end")?;
+        writeln!(out, "$scope module hub75 $end")?;
+        let mut control = vec![("clk", mapping.clk), ("lat", mapping.lat), ("oe", mapping.oe),
+                               ("a", mapping.a), ("b", mapping.b), ("c", mapping.c), ("d", mapping.d)];
+        if let Some(e) = mapping.e {
+            control.push(("e", e));
+        }
+        for (name, pin) in control {
+            declare(&mut out, &mut signals, name, pin)?;
+        }
+        for (chain, pins) in mapping.chains.iter().take(parallel).enumerate() {
+            writeln!(out, "$scope module chain{} $end", chain)?;
+            for &(name, pin) in [("r1", pins.r1), ("g1", pins.g1), ("b1", pins.b1),
+                                 ("r2", pins.r2), ("g2", pins.g2), ("b2", pins.b2)].iter() {
+                declare(&mut out, &mut signals, name, pin)?;
+            }
+            writeln!(out, "$upscope $end")?;
+        }
+        writeln!(out, "$upscope $end")?;
+        writeln!(out, "$enddefinitions $end")?;
+
+        writeln!(out, "#0")?;
+        writeln!(out, "$dumpvars")?;
+        for signal in &signals {
+            writeln!(out, "{}{}", level(levels, signal.pin), signal.id)?;
+        }
+        writeln!(out, "$end")?;
+        Ok(VcdWriter {
+            out,
+            signals,
+            last_nanos: 0,
+        })
+    }
+
+    fn change(&mut self, nanos: u64, before: u32, after: u32) -> io::Result<()> {
+        let changed: Vec<&Signal> = self.signals.iter().filter(|signal| (before ^ after) & GPIO_BIT!(signal.pin) != 0).collect();
+        if changed.is_empty() {
+            return Ok(());
+        }
+        // Writes in quick succession can share a timestamp, also with the
+        // initial values. Spreading them a nanosecond apart keeps every edge
+        // visible.
+        let nanos = nanos.max(self.last_nanos + 1);
+        writeln!(self.out, "#{}", nanos)?;
+        for signal in changed {
+            writeln!(self.out, "{}{}", level(after, signal.pin), signal.id)?;
+        }
+        self.last_nanos = nanos;
+        Ok(())
+    }
+}
+
+fn declare<W: Write>(out: &mut W, signals: &mut Vec<Signal>, name: &str, pin: u64) -> io::Result<()> {
+    // Identifiers are printable ASCII characters, starting at '!'.
+    let id = (b'!' + signals.len() as u8) as char;
+    writeln!(out, "$var wire 1 {} {} $end", id, name)?;
+    signals.push(Signal { id, pin });
+    Ok(())
+}
+
+fn level(levels: u32, pin: u64) -> char {
+    if levels & GPIO_BIT!(pin) != 0 { '1' } else { '0' }
+}
+
+/// Passes writes on to another register block and logs every change of the
+/// HUB75 pins to a VCD file, which GTKWave can show as waveforms. Works the
+/// same on top of the mapped GPIO block or a `FakeRegisterBlock`.
+pub struct TracingRegisterBlock<R: RegisterBlock, W: Write, C: Clock = MonotonicClock> {
+    registers: R,
+    clock: C,
+    vcd: VcdWriter<W>,
+    levels: u32,
+    start_nanos: u64,
+    failed: bool,
+}
+
+impl<R: RegisterBlock, W: Write> TracingRegisterBlock<R, W> {
+    pub fn new(registers: R, out: W, mapping: &PinMapping, parallel: usize) -> io::Result<TracingRegisterBlock<R, W>> {
+        TracingRegisterBlock::with_clock(registers, out, mapping, parallel, MonotonicClock::new())
+    }
+}
+
+impl<R: RegisterBlock, W: Write, C: Clock> TracingRegisterBlock<R, W, C> {
+    pub fn with_clock(registers: R, out: W, mapping: &PinMapping, parallel: usize, clock: C) -> io::Result<TracingRegisterBlock<R, W, C>> {
+        let levels = registers.read(GPLEV0);
+        let vcd = VcdWriter::new(out, mapping, parallel, levels)?;
+        let start_nanos = clock.now_nanos();
+        Ok(TracingRegisterBlock {
+            registers,
+            clock,
+            vcd,
+            levels,
+            start_nanos,
+            failed: false,
+        })
+    }
+}
+
+impl<R: RegisterBlock, W: Write, C: Clock> RegisterBlock for TracingRegisterBlock<R, W, C> {
+    fn read(&self, word: usize) -> u32 {
+        self.registers.read(word)
+    }
+
+    fn write(&mut self, word: usize, value: u32) {
+        self.registers.write(word, value);
+        let levels = match word {
+            GPSET0 => self.levels | value,
+            GPCLR0 => self.levels & !value,
+            _ => return,
+        };
+        // The refresh cannot stop for a failed trace, it only stops tracing.
+        if !self.failed {
+            let nanos = self.clock.now_nanos().wrapping_sub(self.start_nanos);
+            if let Err(why) = self.vcd.change(nanos, self.levels, levels) {
+                eprintln!("Could not write the GPIO trace: {}", why);
+                self.failed = true;
+            }
+        }
+        self.levels = levels;
+    }
+}
+
+#[cfg(test)]
+fn trace_lines(trace: &[u8]) -> Vec<String> {
+    String::from_utf8(trace.to_vec()).unwrap().lines().map(String::from).collect()
+}
+
+#[test]
+fn vcd_header_declares_pins_test() {
+    let pins = PinMapping::regular();
+    let mut trace = Vec::new();
+    let registers = TracingRegisterBlock::with_clock(FakeRegisterBlock::new(), &mut trace, &pins, 2, Rc::new(FakeClock::new(0))).unwrap();
+    drop(registers);
+
+    let lines = trace_lines(&trace);
+    assert!(lines.contains(&String::from("$timescale 1ns $end")));
+    assert!(lines.contains(&String::from("$var wire 1 ! clk $end")));
+    assert!(lines.contains(&String::from("$scope module chain1 $end")));
+    // Eight control and address pins, six color pins per chain.
+    assert_eq!(8 + 2 * 6, lines.iter().filter(|line| line.starts_with("$var")).count());
+    assert_eq!(Some(&String::from("$end")), lines.last());
+}
+
+#[test]
+fn vcd_records_pin_changes_test() {
+    let pins = PinMapping::adafruit_hat();
+    let clock = Rc::new(FakeClock::new(0));
+    let mut trace = Vec::new();
+    let mut registers = TracingRegisterBlock::with_clock(FakeRegisterBlock::new(), &mut trace, &pins, 1, Rc::clone(&clock)).unwrap();
+
+    clock.sleep(1500);
+    registers.write(GPSET0, GPIO_BIT!(pins.clk) | GPIO_BIT!(pins.chains[0].r1));
+    registers.write(GPSET0, GPIO_BIT!(pins.clk));
+    registers.write(GPCLR0, GPIO_BIT!(pins.clk));
+
+    assert_eq!(GPIO_BIT!(pins.chains[0].r1), registers.read(GPLEV0));
+    drop(registers);
+    let lines = trace_lines(&trace);
+    // The changes follow the initial values, which end with "$end".
+    let dumpvars = lines.iter().position(|line| line == "$dumpvars").unwrap();
+    let header_lines = dumpvars + lines[dumpvars..].iter().position(|line| line == "$end").unwrap() + 1;
+    let clk = "!";
+    let r1 = lines.iter().find(|line| line.ends_with(" r1 $end")).unwrap().split(' ').nth(3).unwrap().to_string();
+    assert_eq!(vec![
+        String::from("#1500"), format!("1{}", clk), format!("1{}", r1),
+        // Repeating a write changes nothing, the clearing write follows
+        // within the same nanosecond.
+        String::from("#1501"), format!("0{}", clk),
+    ], lines[header_lines..].to_vec());
+}
+
+#[test]
+fn trace_refresh_from_memory_test() {
+    let pins = PinMapping::adafruit_hat();
+    let config = PanelConfig::default();
+    let clock = Rc::new(FakeClock::new(0));
+    let mut trace = Vec::new();
+    let registers = TracingRegisterBlock::with_clock(FakeRegisterBlock::new(), &mut trace, &pins, 1, Rc::clone(&clock)).unwrap();
+    let mut gpio = GPIO::with_registers(registers, 0, &config, &pins).unwrap();
+    let mut frame = Frame::with_mapper(&config, &IdentityMapper).unwrap();
+    frame.pixels[0][0] = Pixel::new_colored_pixel(255, 255, 255);
+
+    gpio.load_frame(&frame);
+    gpio.refresh(&Timer::with_clock(clock));
+    drop(gpio);
+
+    let lines = trace_lines(&trace);
+    let lat_id = "\"";
+    let latches = lines.iter().filter(|line| *line == &format!("1{}", lat_id)).count();
+    assert_eq!(config.scan_rate * 8, latches, "Every row and plane should be latched once");
+    let timestamps: Vec<u64> = lines.iter().filter(|line| line.starts_with('#')).map(|line| line[1..].parse().unwrap()).collect();
+    assert!(timestamps.windows(2).all(|pair| pair[0] < pair[1]), "Timestamps are not increasing");
+}
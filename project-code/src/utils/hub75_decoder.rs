@@ -0,0 +1,304 @@
+// Only built for tests: it checks what the driver sends without a panel.
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::backend::{Backend, GpioMatrix};
+use super::frame::Frame;
+use super::gamma::{ColorCurve, ColorLut};
+use super::gpio_driver::{GPCLR0, GPIO, GPLEV0, GPSET0, MAX_PWM_BITS};
+use super::panel_config::PanelConfig;
+use super::pin_mapping::{ColorPins, PinMapping};
+use super::pixel::Pixel;
+use super::pixel_mapper::IdentityMapper;
+use super::register_block::{FakeRegisterBlock, RegisterBlock};
+use super::time::{FakeClock, Timer};
+
+/// One row of pixels as the panel showed it: the shift register contents at
+/// the time of the latch, and how long OE enabled the output afterwards.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LatchedRow {
+    /// The double row selected by the A-E address pins.
+    pub address: usize,
+    /// The color pin levels clocked in, first column first.
+    pub columns: Vec<u32>,
+    pub on_nanos: u64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ProtocolError {
+    /// Latching with the output enabled shows half-shifted rows.
+    LatchWhileEnabled { latch: usize },
+    /// Changing rows with the output enabled makes the previous row ghost.
+    AddressChangeWhileEnabled { latch: usize },
+}
+
+/// Stand-in for the GPIO register block that follows the HUB75 signals the
+/// way a panel would: color pins are sampled on rising clock edges, shown
+/// on a rising latch edge, and lit while OE is low. Time comes from the
+/// clock the driver's timer sleeps on.
+pub struct Hub75Decoder {
+    registers: FakeRegisterBlock,
+    clock: Rc<FakeClock>,
+    config: PanelConfig,
+    mapping: PinMapping,
+    shift_register: Vec<u32>,
+    latches: Vec<LatchedRow>,
+    enabled_since: Option<u64>,
+    errors: Vec<ProtocolError>,
+}
+
+impl Hub75Decoder {
+    pub fn new(clock: Rc<FakeClock>, config: &PanelConfig, mapping: &PinMapping) -> Hub75Decoder {
+        let registers = FakeRegisterBlock::new();
+        // OE is pulled up on the panels: the output starts disabled.
+        registers.set_word(GPLEV0, GPIO_BIT!(mapping.oe));
+        Hub75Decoder {
+            registers,
+            clock,
+            config: *config,
+            mapping: mapping.clone(),
+            shift_register: Vec::new(),
+            latches: Vec::new(),
+            enabled_since: None,
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn latches(&self) -> &[LatchedRow] {
+        &self.latches
+    }
+
+    pub fn errors(&self) -> &[ProtocolError] {
+        &self.errors
+    }
+
+    /// Forgets what was shown so far, e.g. to decode a single refresh.
+    pub fn clear(&mut self) {
+        self.latches.clear();
+        self.errors.clear();
+        self.registers.clear_writes();
+    }
+
+    /// The physical pixels with every channel the sum of the bitplanes it was
+    /// lit in, counting from the plane shown for `lsb_nanos`. The plane of a
+    /// latch is the power of two nearest to its on-time.
+    pub fn decode_image(&self, lsb_nanos: u64) -> Vec<Vec<Pixel>> {
+        self.accumulate(|latch| {
+            let planes = (latch.on_nanos as f64 / lsb_nanos as f64).log2().round();
+            if planes < 0.0 { 0 } else { 1 << planes as u32 }
+        })
+    }
+
+    /// How long every channel of the physical pixels was lit, in nanoseconds.
+    pub fn on_nanos(&self) -> Vec<Vec<[u64; 3]>> {
+        let mut on_nanos = vec![vec![[0; 3]; self.config.canvas_width()]; self.config.canvas_height()];
+        self.for_each_lit(|row, col, channel, latch| on_nanos[row][col][channel] += latch.on_nanos);
+        on_nanos
+    }
+
+    fn accumulate<F: Fn(&LatchedRow) -> u16>(&self, weight: F) -> Vec<Vec<Pixel>> {
+        let mut pixels = vec![vec![Pixel::new(); self.config.canvas_width()]; self.config.canvas_height()];
+        self.for_each_lit(|row, col, channel, latch| {
+            let pixel = &mut pixels[row][col];
+            let value = weight(latch);
+            match channel {
+                0 => pixel.r += value,
+                1 => pixel.g += value,
+                _ => pixel.b += value,
+            }
+        });
+        pixels
+    }
+
+    // Calls `lit` with the row, column and channel of every LED a latch
+    // turned on.
+    fn for_each_lit<F: FnMut(usize, usize, usize, &LatchedRow)>(&self, mut lit: F) {
+        for latch in &self.latches {
+            for (col, bits) in latch.columns.iter().enumerate() {
+                for (chain, pins) in self.mapping.chains.iter().take(self.config.parallel).enumerate() {
+                    let top_row = chain * self.config.height + latch.address;
+                    let bottom_row = top_row + self.config.scan_rate;
+                    for &(row, channel, pin) in channel_pins(top_row, bottom_row, pins).iter() {
+                        if bits & GPIO_BIT!(pin) != 0 {
+                            lit(row, col, channel, latch);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn row_address(&self, levels: u32) -> usize {
+        let mut address_pins = vec![self.mapping.a, self.mapping.b, self.mapping.c, self.mapping.d];
+        address_pins.extend(self.mapping.e);
+        address_pins.iter().enumerate()
+            .filter(|(_bit, pin)| levels & GPIO_BIT!(**pin) != 0)
+            .fold(0, |address, (bit, _pin)| address | 1 << bit)
+    }
+
+    fn on_edges(&mut self, before: u32, after: u32) {
+        let rising = !before & after;
+        let falling = before & !after;
+        let enabled = after & GPIO_BIT!(self.mapping.oe) == 0;
+
+        if rising & GPIO_BIT!(self.mapping.clk) != 0 {
+            self.shift_register.push(after & self.mapping.color_bits(self.config.parallel));
+        }
+        if enabled && self.row_address(before) != self.row_address(after) {
+            self.errors.push(ProtocolError::AddressChangeWhileEnabled { latch: self.latches.len() });
+        }
+        if rising & GPIO_BIT!(self.mapping.lat) != 0 {
+            if enabled {
+                self.errors.push(ProtocolError::LatchWhileEnabled { latch: self.latches.len() });
+            }
+            // Whatever was clocked in before the last canvas width fell out
+            // of the end of the chain.
+            let columns = self.config.canvas_width();
+            let start = self.shift_register.len().saturating_sub(columns);
+            let columns = self.shift_register.split_off(start);
+            self.shift_register.clear();
+            let address = self.row_address(after);
+            self.latches.push(LatchedRow { address, columns, on_nanos: 0 });
+        }
+        if falling & GPIO_BIT!(self.mapping.oe) != 0 {
+            self.enabled_since = Some(self.clock.now_nanos());
+        }
+        if rising & GPIO_BIT!(self.mapping.oe) != 0 {
+            if let (Some(since), Some(latch)) = (self.enabled_since.take(), self.latches.last_mut()) {
+                latch.on_nanos += self.clock.now_nanos() - since;
+            }
+        }
+    }
+}
+
+impl RegisterBlock for Hub75Decoder {
+    fn read(&self, word: usize) -> u32 {
+        self.registers.read(word)
+    }
+
+    fn write(&mut self, word: usize, value: u32) {
+        let before = self.registers.read(GPLEV0);
+        self.registers.write(word, value);
+        if word == GPSET0 || word == GPCLR0 {
+            let after = self.registers.read(GPLEV0);
+            self.on_edges(before, after);
+        }
+    }
+}
+
+// Lets a test keep a handle on the decoder the driver writes to.
+impl RegisterBlock for Rc<RefCell<Hub75Decoder>> {
+    fn read(&self, word: usize) -> u32 {
+        self.borrow().read(word)
+    }
+
+    fn write(&mut self, word: usize, value: u32) {
+        self.borrow_mut().write(word, value)
+    }
+}
+
+fn channel_pins(top_row: usize, bottom_row: usize, pins: &ColorPins) -> [(usize, usize, u64); 6] {
+    [
+        (top_row, 0, pins.r1), (top_row, 1, pins.g1), (top_row, 2, pins.b1),
+        (bottom_row, 0, pins.r2), (bottom_row, 1, pins.g2), (bottom_row, 2, pins.b2),
+    ]
+}
+
+type DecodingMatrix = GpioMatrix<Rc<RefCell<Hub75Decoder>>, Rc<FakeClock>>;
+
+fn decoding_matrix(config: &PanelConfig, pins: &PinMapping, pwm_bits: usize, lsb_nanos: u32) -> (DecodingMatrix, Rc<RefCell<Hub75Decoder>>) {
+    // Reads of the microsecond counter need to be cheap next to the LSB for
+    // the on-times to come out close to what was asked for.
+    let clock = Rc::new(FakeClock::new(0).with_tick(10));
+    let decoder = Rc::new(RefCell::new(Hub75Decoder::new(Rc::clone(&clock), config, pins)));
+    let mut gpio = GPIO::with_registers(Rc::clone(&decoder), 0, config, pins).unwrap();
+    gpio.set_pwm_bits(pwm_bits).unwrap();
+    gpio.set_pwm_lsb_nanos(lsb_nanos).unwrap();
+    gpio.set_color_lut(ColorLut::new([ColorCurve::Gamma(1.0); 3], MAX_PWM_BITS));
+    (GpioMatrix::new(gpio, Timer::with_clock(clock)), decoder)
+}
+
+fn test_pattern(config: &PanelConfig) -> Frame {
+    let mut frame = Frame::with_mapper(config, &IdentityMapper).unwrap();
+    for (row, pixels) in frame.pixels.iter_mut().enumerate() {
+        for (col, pixel) in pixels.iter_mut().enumerate() {
+            *pixel = Pixel::new_colored_pixel((row * 16 + col) as u16 % 256, (col * 8) as u16 % 256, (255 - row * 4) as u16);
+        }
+    }
+    frame
+}
+
+#[test]
+fn decoded_refresh_matches_frame_test() {
+    let pins = PinMapping::regular();
+    let config = PanelConfig::default().chained(2, 2).unwrap();
+    let (pwm_bits, lsb_nanos) = (8, 10 * 1000);
+    let (mut matrix, decoder) = decoding_matrix(&config, &pins, pwm_bits, lsb_nanos);
+    let frame = test_pattern(&config);
+    matrix.load_frame(&frame);
+    decoder.borrow_mut().clear();
+
+    matrix.refresh(&frame);
+
+    let decoder = decoder.borrow();
+    assert!(decoder.errors().is_empty(), "Protocol errors: {:?}", decoder.errors());
+    assert_eq!(config.scan_rate * pwm_bits, decoder.latches().len());
+    let lut = ColorLut::new([ColorCurve::Gamma(1.0); 3], MAX_PWM_BITS);
+    let first_plane = MAX_PWM_BITS - pwm_bits;
+    let decoded = decoder.decode_image(lsb_nanos as u64);
+    let on_nanos = decoder.on_nanos();
+    for row in 0..config.canvas_height() {
+        for col in 0..config.canvas_width() {
+            let expected = lut.map(frame.physical_pixel(row, col));
+            let planes = [expected.r >> first_plane, expected.g >> first_plane, expected.b >> first_plane];
+            assert_eq!(Pixel::new_colored_pixel(planes[0], planes[1], planes[2]), decoded[row][col], "Pixel {},{} differs", row, col);
+            for channel in 0..3 {
+                // The microsecond counter makes every plane up to 1us short
+                // or long.
+                let duty = planes[channel] as i64 * lsb_nanos as i64;
+                let slack = pwm_bits as i64 * 1100;
+                assert!((on_nanos[row][col][channel] as i64 - duty).abs() <= slack,
+                        "Pixel {},{} channel {} was on for {}ns instead of {}ns", row, col, channel, on_nanos[row][col][channel], duty);
+            }
+        }
+    }
+}
+
+#[test]
+fn decoded_rows_follow_bitplane_order_test() {
+    let pins = PinMapping::adafruit_hat();
+    let config = PanelConfig::default();
+    let (pwm_bits, lsb_nanos) = (4, 20 * 1000);
+    let (mut matrix, decoder) = decoding_matrix(&config, &pins, pwm_bits, lsb_nanos);
+    let frame = Frame::with_mapper(&config, &IdentityMapper).unwrap();
+    matrix.load_frame(&frame);
+    decoder.borrow_mut().clear();
+
+    matrix.refresh(&frame);
+
+    let decoder = decoder.borrow();
+    let latches = decoder.latches();
+    for (index, latch) in latches.iter().enumerate() {
+        assert_eq!(index / pwm_bits, latch.address, "Rows are not scanned in order");
+        assert_eq!(config.canvas_width(), latch.columns.len());
+        let expected = (lsb_nanos as u64) << (index % pwm_bits);
+        assert!(latch.on_nanos >= expected - 1000 && latch.on_nanos <= expected + 1000,
+                "Latch {} was on for {}ns instead of {}ns", index, latch.on_nanos, expected);
+    }
+}
+
+#[test]
+fn latch_while_enabled_is_reported_test() {
+    let pins = PinMapping::adafruit_hat();
+    let config = PanelConfig::default();
+    let mut decoder = Hub75Decoder::new(Rc::new(FakeClock::new(0)), &config, &pins);
+    decoder.write(GPCLR0, GPIO_BIT!(pins.oe));
+    decoder.write(GPSET0, GPIO_BIT!(pins.a));
+    decoder.write(GPSET0, GPIO_BIT!(pins.lat));
+
+    assert_eq!(&[
+        ProtocolError::AddressChangeWhileEnabled { latch: 0 },
+        ProtocolError::LatchWhileEnabled { latch: 0 },
+    ], decoder.errors());
+    assert_eq!(1, decoder.latches()[0].address);
+}
@@ -1,6 +1,11 @@
+use std::io;
+use std::io::Write;
+
 use super::pixel::Pixel;
-use super::gpio_driver::{COLUMNS, ROWS};
+#[cfg(test)]
+use super::file_reader::PpmStreamReader;
 
+#[derive(Clone)]
 pub struct Image {
     pub width: u32,
     pub height: u32,
@@ -8,12 +13,35 @@ pub struct Image {
 }
 
 impl Image {
-    pub fn new() -> Image {
-        let mut image: Image = Image {
-            width: COLUMNS as u32,
-            height: ROWS as u32,
-            pixels: vec![vec![Pixel::new(); COLUMNS as usize]; ROWS as usize],
-        };
-        image
+    /// Three bytes per pixel, row by row. Channels above 255 are clipped.
+    pub fn to_rgb_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.width as usize * self.height as usize * 3);
+        for row in &self.pixels {
+            for pixel in row {
+                bytes.extend_from_slice(&[pixel.r.min(255) as u8, pixel.g.min(255) as u8, pixel.b.min(255) as u8]);
+            }
+        }
+        bytes
+    }
+
+    /// Writes the image as a binary PPM (P6) with a maximum value of 255.
+    pub fn write_ppm<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        out.write_all(&self.to_rgb_bytes())
     }
 }
+
+#[test]
+fn write_ppm_round_trip_test() {
+    let image = Image {
+        width: 2,
+        height: 1,
+        pixels: vec![vec![Pixel::new_colored_pixel(255, 128, 0), Pixel::new_colored_pixel(1, 2, 3)]],
+    };
+    let mut ppm: Vec<u8> = Vec::new();
+    image.write_ppm(&mut ppm).unwrap();
+    assert!(ppm.starts_with(b"P6\n2 1\n255\n"));
+
+    let decoded = PpmStreamReader::new(io::Cursor::new(ppm)).next_image().unwrap().unwrap();
+    assert_eq!(image.pixels, decoded.pixels);
+}
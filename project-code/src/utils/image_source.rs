@@ -0,0 +1,175 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::time::Duration;
+
+#[cfg(feature = "bmp")]
+use super::bmp_source::BmpSource;
+use super::file_reader::{PpmError, PpmStreamReader};
+#[cfg(feature = "gif")]
+use super::gif_source::GifSource;
+use super::image::Image;
+use super::pixel::Pixel;
+#[cfg(feature = "png")]
+use super::png_source::PngSource;
+
+/// Produces the images of a still picture, an animation or a video stream.
+pub trait ImageSource {
+    /// Returns `Ok(None)` when there are no more images.
+    fn next_image(&mut self) -> Result<Option<Image>, SourceError>;
+
+    /// How long the image last returned by `next_image` should be shown, if
+    /// the format says so.
+    fn frame_delay(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ImageFormat {
+    Netpbm,
+    Png,
+    Gif,
+    Bmp,
+}
+
+impl ImageFormat {
+    pub fn from_magic(magic: &[u8]) -> Option<ImageFormat> {
+        if magic.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageFormat::Png)
+        } else if magic.starts_with(b"GIF87a") || magic.starts_with(b"GIF89a") {
+            Some(ImageFormat::Gif)
+        } else if magic.starts_with(b"BM") {
+            Some(ImageFormat::Bmp)
+        } else if magic.len() >= 2 && magic[0] == b'P' && magic[1] >= b'1' && magic[1] <= b'7' {
+            Some(ImageFormat::Netpbm)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImageFormat::Netpbm => "Netpbm",
+            ImageFormat::Png => "PNG",
+            ImageFormat::Gif => "GIF",
+            ImageFormat::Bmp => "BMP",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SourceError {
+    Io(io::Error),
+    Ppm(PpmError),
+    Decode(ImageFormat, String),
+    UnknownFormat,
+    Unsupported(ImageFormat),
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SourceError::Io(why) => write!(f, "I/O error: {}", why),
+            SourceError::Ppm(why) => write!(f, "{}", why),
+            SourceError::Decode(format, reason) => write!(f, "Invalid {} data: {}", format.name(), reason),
+            SourceError::UnknownFormat => write!(f, "Unknown image format, expected Netpbm, PNG, GIF or BMP"),
+            SourceError::Unsupported(format) => write!(f, "Support for {} images was not compiled in", format.name()),
+        }
+    }
+}
+
+impl Error for SourceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SourceError::Io(why) => Some(why),
+            SourceError::Ppm(why) => Some(why),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SourceError {
+    fn from(why: io::Error) -> SourceError {
+        SourceError::Io(why)
+    }
+}
+
+impl From<PpmError> for SourceError {
+    fn from(why: PpmError) -> SourceError {
+        SourceError::Ppm(why)
+    }
+}
+
+impl<R: Read> ImageSource for PpmStreamReader<R> {
+    fn next_image(&mut self) -> Result<Option<Image>, SourceError> {
+        Ok(PpmStreamReader::next_image(self)?)
+    }
+}
+
+pub fn open_image_source(path: &Path) -> Result<Box<dyn ImageSource + Send>, SourceError> {
+    image_source_from_reader(File::open(path)?)
+}
+
+/// Picks the decoder from the magic bytes at the start of `reader`.
+pub fn image_source_from_reader<R: Read + Send + 'static>(reader: R) -> Result<Box<dyn ImageSource + Send>, SourceError> {
+    let mut reader = BufReader::new(reader);
+    let format = match ImageFormat::from_magic(reader.fill_buf()?) {
+        Some(format) => format,
+        None => return Err(SourceError::UnknownFormat),
+    };
+
+    match format {
+        ImageFormat::Netpbm => Ok(Box::new(PpmStreamReader::new(reader))),
+        #[cfg(feature = "png")]
+        ImageFormat::Png => Ok(Box::new(PngSource::new(reader))),
+        #[cfg(feature = "gif")]
+        ImageFormat::Gif => Ok(Box::new(GifSource::new(reader)?)),
+        #[cfg(feature = "bmp")]
+        ImageFormat::Bmp => Ok(Box::new(BmpSource::new(reader))),
+        #[allow(unreachable_patterns)]
+        format => Err(SourceError::Unsupported(format)),
+    }
+}
+
+// Blends a pixel with straight alpha onto black, which is what an unlit LED
+// looks like.
+pub fn blend_onto_black(r: u8, g: u8, b: u8, alpha: u8) -> Pixel {
+    let blend = |value: u8| (value as u16 * alpha as u16 + 127) / 255;
+    Pixel::new_colored_pixel(blend(r), blend(g), blend(b))
+}
+
+#[test]
+fn image_format_from_magic_test() {
+    assert_eq!(Some(ImageFormat::Png), ImageFormat::from_magic(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"));
+    assert_eq!(Some(ImageFormat::Gif), ImageFormat::from_magic(b"GIF89a"));
+    assert_eq!(Some(ImageFormat::Bmp), ImageFormat::from_magic(b"BM6\0\0\0"));
+    assert_eq!(Some(ImageFormat::Netpbm), ImageFormat::from_magic(b"P6\n32 16\n"));
+    assert_eq!(None, ImageFormat::from_magic(b"P8\n"));
+    assert_eq!(None, ImageFormat::from_magic(b""));
+}
+
+#[test]
+fn open_image_source_sniffs_format_test() {
+    for name in &["resources/netpbm/pixmap_binary.ppm", "resources/image_source/pixmap.png",
+                  "resources/image_source/pixmap.bmp", "resources/image_source/pixmap.gif"] {
+        let mut source = open_image_source(Path::new(name)).unwrap();
+        let image = source.next_image().unwrap().unwrap();
+        assert_eq!((3, 2), (image.width, image.height), "Wrong size for {}", name);
+        assert_eq!(vec![Pixel::new_colored_pixel(255, 0, 0), Pixel::new_colored_pixel(0, 255, 0), Pixel::new_colored_pixel(0, 0, 255)],
+                   image.pixels[0], "Wrong first row for {}", name);
+        assert!(source.next_image().unwrap().is_none(), "More than one image in {}", name);
+    }
+}
+
+#[test]
+fn open_image_source_rejects_unknown_format_test() {
+    match image_source_from_reader(io::Cursor::new(b"hello".to_vec())) {
+        Err(SourceError::UnknownFormat) => {},
+        Err(why) => panic!("Expected an unknown format error, got {}", why),
+        Ok(_) => panic!("Expected an unknown format error"),
+    }
+}
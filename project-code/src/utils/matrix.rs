@@ -0,0 +1,140 @@
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+
+use super::backend::Backend;
+use super::frame::Frame;
+use super::realtime::{RealtimeError, RealtimeSettings};
+#[cfg(test)]
+use super::gpio_driver::GPSET0;
+#[cfg(test)]
+use super::pixel::Pixel;
+#[cfg(test)]
+use super::pixel_mapper::IdentityMapper;
+#[cfg(test)]
+use super::register_block::{FakePanel, RegisterWrite};
+
+/// Keeps the panel refreshed from a background thread, so drawing never
+/// stalls the refresh. Draw into a frame of your own and hand it over with
+/// `swap_on_vsync`.
+pub struct Matrix<B: Backend + Send + 'static = Box<dyn Backend + Send>> {
+    state: Arc<SwapState>,
+    refresh_thread: Option<JoinHandle<B>>,
+}
+
+struct SwapState {
+    frames: Mutex<FrameSlots>,
+    swapped: Condvar,
+    running: AtomicBool,
+}
+
+// `next` waits to be shown, `previous` waits to be picked up by the caller.
+struct FrameSlots {
+    next: Option<Frame>,
+    previous: Option<Frame>,
+}
+
+impl<B: Backend + Send + 'static> Matrix<B> {
+    /// Starts refreshing `front`, which should have the same size as the
+    /// frames passed to `swap_on_vsync` later on. Fails if the refresh thread
+    /// cannot be given the `realtime` settings.
+    pub fn start(backend: B, front: Frame, realtime: RealtimeSettings) -> Result<Matrix<B>, RealtimeError> {
+        let state = Arc::new(SwapState {
+            frames: Mutex::new(FrameSlots { next: None, previous: None }),
+            swapped: Condvar::new(),
+            running: AtomicBool::new(true),
+        });
+        let thread_state = state.clone();
+        let (started, start_result) = mpsc::channel();
+        let refresh_thread = thread::spawn(move || {
+            let applied = realtime.apply_to_current_thread();
+            let failed = applied.is_err();
+            started.send(applied).unwrap();
+            if failed {
+                return backend;
+            }
+            refresh_loop(backend, front, thread_state)
+        });
+
+        if let Err(why) = start_result.recv().unwrap() {
+            refresh_thread.join().expect("The refresh thread panicked");
+            return Err(why);
+        }
+        Ok(Matrix {
+            state,
+            refresh_thread: Some(refresh_thread),
+        })
+    }
+
+    /// Hands `canvas` to the refresh thread and blocks until it is being
+    /// shown, which only happens between two complete refreshes. Returns the
+    /// frame that was shown before, to draw the next one in.
+    pub fn swap_on_vsync(&self, canvas: Frame) -> Frame {
+        let mut slots = self.state.frames.lock().unwrap();
+        slots.next = Some(canvas);
+        loop {
+            if let Some(previous) = slots.previous.take() {
+                return previous;
+            }
+            slots = self.state.swapped.wait(slots).unwrap();
+        }
+    }
+
+    /// Stops refreshing, switches the panel off and gives back the backend.
+    pub fn stop(mut self) -> B {
+        self.join().expect("The refresh thread was already stopped")
+    }
+
+    fn join(&mut self) -> Option<B> {
+        self.state.running.store(false, Ordering::SeqCst);
+        self.refresh_thread.take().map(|thread| thread.join().expect("The refresh thread panicked"))
+    }
+}
+
+impl<B: Backend + Send + 'static> Drop for Matrix<B> {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+fn refresh_loop<B: Backend>(mut backend: B, mut front: Frame, state: Arc<SwapState>) -> B {
+    backend.load_frame(&front);
+    while state.running.load(Ordering::SeqCst) {
+        // Never wait for the drawing thread, a late swap is better than a
+        // stalled refresh.
+        if let Ok(mut slots) = state.frames.try_lock() {
+            if let Some(next) = slots.next.take() {
+                backend.load_frame(&next);
+                slots.previous = Some(mem::replace(&mut front, next));
+                state.swapped.notify_all();
+            }
+        }
+        backend.refresh(&front);
+    }
+    backend.blank();
+    backend
+}
+
+#[test]
+fn swap_on_vsync_returns_previous_frame_test() {
+    let panel = FakePanel::new();
+    let mut front = Frame::with_mapper(&panel.config, &IdentityMapper).unwrap();
+    front.pixels[1][1] = Pixel::new_colored_pixel(0, 0, 255);
+    let matrix = Matrix::start(panel.matrix(), front, RealtimeSettings::default()).unwrap();
+
+    let mut canvas = Frame::with_mapper(&panel.config, &IdentityMapper).unwrap();
+    canvas.pixels[0][0] = Pixel::new_colored_pixel(255, 0, 0);
+    let previous = matrix.swap_on_vsync(canvas);
+    assert_eq!(Pixel::new_colored_pixel(0, 0, 255), previous.pixels[1][1]);
+
+    let shown = matrix.swap_on_vsync(previous);
+    assert_eq!(Pixel::new_colored_pixel(255, 0, 0), shown.pixels[0][0]);
+
+    matrix.stop();
+    let writes = panel.registers.writes();
+    assert!(writes.contains(&RegisterWrite { word: GPSET0, value: GPIO_BIT!(panel.pins.chains[0].r1) }), "The swapped in frame was never sent");
+    assert_eq!(Some(&RegisterWrite { word: GPSET0, value: GPIO_BIT!(panel.pins.oe) }), writes.last(), "The panel should be off after stopping");
+}
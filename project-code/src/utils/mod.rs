@@ -1,7 +1,31 @@
+#[macro_use]
+pub mod pin_mapping;
+pub mod animation;
+pub mod backend;
+pub mod bitplane_buffer;
+#[cfg(feature = "bmp")]
+pub mod bmp_source;
 pub mod file_reader;
+#[cfg(feature = "gif")]
+pub mod gif_source;
 pub mod image;
+pub mod image_source;
+pub mod matrix;
 pub mod pixel;
+pub mod pixel_mapper;
 pub mod frame;
+pub mod gamma;
 pub mod gpio_driver;
+pub mod gpio_trace;
+#[cfg(test)]
+pub mod hub75_decoder;
+pub mod panel_config;
+pub mod pi_model;
+#[cfg(feature = "png")]
+pub mod png_source;
+pub mod realtime;
+pub mod recorder;
+pub mod register_block;
+pub mod resample;
+pub mod terminal_matrix;
 pub mod time;
-
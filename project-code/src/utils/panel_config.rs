@@ -0,0 +1,98 @@
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PanelConfig {
+    pub width: usize,
+    pub height: usize,
+    pub scan_rate: usize,
+    pub chain_length: usize,
+    pub parallel: usize,
+}
+
+impl PanelConfig {
+    pub fn new(width: usize, height: usize, scan_rate: usize) -> Result<PanelConfig, String> {
+        if width == 0 {
+            return Err(String::from("Panel width must be at least 1"));
+        }
+        if !scan_rate.is_power_of_two() || !(2..=32).contains(&scan_rate) {
+            return Err(format!("Scan rate must be 2, 4, 8, 16 or 32 (got {})", scan_rate));
+        }
+        if height == 0 || !height.is_multiple_of(scan_rate) {
+            return Err(format!("Panel height {} is not a multiple of the scan rate {}", height, scan_rate));
+        }
+
+        Ok(PanelConfig {
+            width,
+            height,
+            scan_rate,
+            chain_length: 1,
+            parallel: 1,
+        })
+    }
+
+    pub fn chained(self, chain_length: usize, parallel: usize) -> Result<PanelConfig, String> {
+        if chain_length == 0 {
+            return Err(String::from("Chain length must be at least 1"));
+        }
+        if parallel == 0 {
+            return Err(String::from("Number of parallel chains must be at least 1"));
+        }
+
+        Ok(PanelConfig {
+            chain_length,
+            parallel,
+            ..self
+        })
+    }
+
+    pub fn sub_panels(&self) -> usize {
+        self.height / self.scan_rate
+    }
+
+    pub fn canvas_width(&self) -> usize {
+        self.width * self.chain_length
+    }
+
+    pub fn canvas_height(&self) -> usize {
+        self.height * self.parallel
+    }
+}
+
+impl Default for PanelConfig {
+    fn default() -> PanelConfig {
+        PanelConfig {
+            width: 32,
+            height: 16,
+            scan_rate: 8,
+            chain_length: 1,
+            parallel: 1,
+        }
+    }
+}
+
+#[test]
+fn panel_config_sub_panels_test() {
+    assert_eq!(2, PanelConfig::default().sub_panels());
+    assert_eq!(2, PanelConfig::new(64, 64, 32).unwrap().sub_panels());
+    assert_eq!(4, PanelConfig::new(32, 16, 4).unwrap().sub_panels());
+}
+
+#[test]
+fn panel_config_canvas_size_test() {
+    let config = PanelConfig::default().chained(4, 2).unwrap();
+    assert_eq!(128, config.canvas_width());
+    assert_eq!(32, config.canvas_height());
+}
+
+#[test]
+fn panel_config_rejects_invalid_sizes_test() {
+    assert!(PanelConfig::new(64, 20, 8).is_err());
+    assert!(PanelConfig::new(32, 20, 10).is_err());
+    assert!(PanelConfig::new(32, 16, 3).is_err());
+    assert!(PanelConfig::new(0, 16, 8).is_err());
+    assert!(PanelConfig::new(32, 0, 8).is_err());
+}
+
+#[test]
+fn panel_config_rejects_empty_chains_test() {
+    assert!(PanelConfig::default().chained(0, 1).is_err());
+    assert!(PanelConfig::default().chained(1, 0).is_err());
+}
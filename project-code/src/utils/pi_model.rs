@@ -0,0 +1,84 @@
+use std::fs;
+#[cfg(test)]
+use std::path::Path;
+
+pub const BCM2835_PERI_BASE: u64 = 0x20000000;
+pub const BCM2709_PERI_BASE: u64 = 0x3F000000;
+pub const BCM2711_PERI_BASE: u64 = 0xFE000000;
+
+const DEVICE_TREE_RANGES_PATH: &str = "/proc/device-tree/soc/ranges";
+const CPUINFO_PATH: &str = "/proc/cpuinfo";
+
+pub fn detect_peripheral_base() -> Option<u64> {
+    if let Ok(ranges) = fs::read(DEVICE_TREE_RANGES_PATH) {
+        if let Some(base) = peripheral_base_from_ranges(&ranges) {
+            return Some(base);
+        }
+    }
+    match fs::read_to_string(CPUINFO_PATH) {
+        Ok(cpuinfo) => peripheral_base_from_cpuinfo(&cpuinfo),
+        Err(_err) => None,
+    }
+}
+
+// The first cell of soc/ranges is the bus address of the peripherals, followed
+// by the CPU address. On the Pi 4 the CPU address takes two cells, the first of
+// which is zero.
+pub fn peripheral_base_from_ranges(ranges: &[u8]) -> Option<u64> {
+    let base = read_cell(ranges, 1)?;
+    if base != 0 {
+        return Some(base as u64);
+    }
+    read_cell(ranges, 2).filter(|base| *base != 0).map(|base| base as u64)
+}
+
+pub fn peripheral_base_from_cpuinfo(cpuinfo: &str) -> Option<u64> {
+    let revision = cpuinfo.lines()
+        .filter(|line| line.starts_with("Revision"))
+        .filter_map(|line| line.split(':').nth(1))
+        .filter_map(|value| u32::from_str_radix(value.trim(), 16).ok())
+        .next()?;
+
+    // Old style revision codes were only used on the BCM2835 based models.
+    if revision & (1 << 23) == 0 {
+        return Some(BCM2835_PERI_BASE);
+    }
+    match (revision >> 12) & 0xF {
+        0 => Some(BCM2835_PERI_BASE),
+        1 | 2 => Some(BCM2709_PERI_BASE),
+        3 => Some(BCM2711_PERI_BASE),
+        _ => None,
+    }
+}
+
+fn read_cell(data: &[u8], index: usize) -> Option<u32> {
+    let cell = data.get(index * 4..index * 4 + 4)?;
+    Some((cell[0] as u32) << 24 | (cell[1] as u32) << 16 | (cell[2] as u32) << 8 | cell[3] as u32)
+}
+
+#[cfg(test)]
+fn read_fixture(name: &str) -> Vec<u8> {
+    fs::read(Path::new("resources/pi_model").join(name)).unwrap()
+}
+
+#[test]
+fn peripheral_base_from_ranges_test() {
+    assert_eq!(Some(BCM2835_PERI_BASE), peripheral_base_from_ranges(&read_fixture("pi1_soc_ranges")));
+    assert_eq!(Some(BCM2709_PERI_BASE), peripheral_base_from_ranges(&read_fixture("pi3_soc_ranges")));
+    assert_eq!(Some(BCM2711_PERI_BASE), peripheral_base_from_ranges(&read_fixture("pi4_soc_ranges")));
+    assert_eq!(None, peripheral_base_from_ranges(&[0x7e, 0, 0, 0]));
+}
+
+#[test]
+fn peripheral_base_from_cpuinfo_test() {
+    let cpuinfo = |name| String::from_utf8(read_fixture(name)).unwrap();
+    assert_eq!(Some(BCM2835_PERI_BASE), peripheral_base_from_cpuinfo(&cpuinfo("pi1_cpuinfo")));
+    assert_eq!(Some(BCM2709_PERI_BASE), peripheral_base_from_cpuinfo(&cpuinfo("pi3_cpuinfo")));
+    assert_eq!(Some(BCM2711_PERI_BASE), peripheral_base_from_cpuinfo(&cpuinfo("pi4_cpuinfo")));
+    assert_eq!(None, peripheral_base_from_cpuinfo(&cpuinfo("x86_cpuinfo")));
+}
+
+#[test]
+fn peripheral_base_from_cpuinfo_pi_zero_test() {
+    assert_eq!(Some(BCM2835_PERI_BASE), peripheral_base_from_cpuinfo("Hardware\t: BCM2835\nRevision\t: 9000c1\n"));
+}
@@ -0,0 +1,194 @@
+use super::panel_config::PanelConfig;
+
+const MAX_GPIO_PIN: u64 = 27;
+
+macro_rules! GPIO_BIT {
+    ($bit:expr) => {
+        1 << $bit
+    };
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ColorPins {
+    pub r1: u64,
+    pub g1: u64,
+    pub b1: u64,
+    pub r2: u64,
+    pub g2: u64,
+    pub b2: u64,
+}
+
+impl ColorPins {
+    pub fn bits(&self) -> u32 {
+        GPIO_BIT!(self.r1) | GPIO_BIT!(self.g1) | GPIO_BIT!(self.b1) |
+            GPIO_BIT!(self.r2) | GPIO_BIT!(self.g2) | GPIO_BIT!(self.b2)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PinMapping {
+    pub name: &'static str,
+    pub oe: u64,
+    pub clk: u64,
+    pub lat: u64,
+    pub a: u64,
+    pub b: u64,
+    pub c: u64,
+    pub d: u64,
+    pub e: Option<u64>,
+    pub chains: Vec<ColorPins>,
+}
+
+impl PinMapping {
+    pub fn adafruit_hat() -> PinMapping {
+        PinMapping {
+            name: "adafruit-hat",
+            oe: 4,
+            clk: 17,
+            lat: 21,
+            a: 22,
+            b: 26,
+            c: 27,
+            d: 20,
+            e: Some(24),
+            chains: vec![
+                ColorPins { r1: 5, g1: 13, b1: 6, r2: 12, g2: 16, b2: 23 },
+            ],
+        }
+    }
+
+    pub fn adafruit_hat_pwm() -> PinMapping {
+        PinMapping {
+            name: "adafruit-hat-pwm",
+            oe: 18,
+            ..PinMapping::adafruit_hat()
+        }
+    }
+
+    pub fn regular() -> PinMapping {
+        PinMapping {
+            name: "regular",
+            oe: 18,
+            clk: 17,
+            lat: 4,
+            a: 22,
+            b: 23,
+            c: 24,
+            d: 25,
+            e: Some(15),
+            chains: vec![
+                ColorPins { r1: 11, g1: 27, b1: 7, r2: 8, g2: 9, b2: 10 },
+                ColorPins { r1: 12, g1: 5, b1: 6, r2: 19, g2: 13, b2: 20 },
+                ColorPins { r1: 14, g1: 2, b1: 3, r2: 26, g2: 16, b2: 21 },
+            ],
+        }
+    }
+
+    pub fn classic() -> PinMapping {
+        PinMapping {
+            name: "classic",
+            oe: 27,
+            clk: 11,
+            lat: 4,
+            a: 7,
+            b: 8,
+            c: 9,
+            d: 10,
+            e: None,
+            chains: vec![
+                ColorPins { r1: 17, g1: 18, b1: 22, r2: 23, g2: 24, b2: 25 },
+                ColorPins { r1: 12, g1: 13, b1: 14, r2: 15, g2: 19, b2: 20 },
+                ColorPins { r1: 5, g1: 6, b1: 3, r2: 2, g2: 26, b2: 16 },
+            ],
+        }
+    }
+
+    pub fn by_name(name: &str) -> Option<PinMapping> {
+        match name {
+            "adafruit-hat" => Some(PinMapping::adafruit_hat()),
+            "adafruit-hat-pwm" => Some(PinMapping::adafruit_hat_pwm()),
+            "regular" => Some(PinMapping::regular()),
+            "classic" => Some(PinMapping::classic()),
+            _ => None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.chains.is_empty() {
+            return Err(format!("Pin mapping {} has no color pins", self.name));
+        }
+
+        let mut used_bits: u32 = 0;
+        for pin in self.pins() {
+            if pin > MAX_GPIO_PIN {
+                return Err(format!("Pin mapping {} uses GPIO {}, which is out of range (0-{})", self.name, pin, MAX_GPIO_PIN));
+            }
+            if used_bits & GPIO_BIT!(pin) != 0 {
+                return Err(format!("Pin mapping {} uses GPIO {} more than once", self.name, pin));
+            }
+            used_bits |= GPIO_BIT!(pin);
+        }
+        Ok(())
+    }
+
+    pub fn check_panel(&self, config: &PanelConfig) -> Result<(), String> {
+        if config.parallel > self.chains.len() {
+            return Err(format!("Pin mapping {} supports at most {} parallel chain(s)", self.name, self.chains.len()));
+        }
+        if config.scan_rate > 16 && self.e.is_none() {
+            return Err(format!("Pin mapping {} has no E address line, which a 1:{} scan panel needs", self.name, config.scan_rate));
+        }
+        Ok(())
+    }
+
+    pub fn valid_bits(&self) -> u32 {
+        self.pins().iter().fold(0, |bits, pin| bits | GPIO_BIT!(*pin))
+    }
+
+    pub fn color_bits(&self, parallel: usize) -> u32 {
+        self.chains.iter().take(parallel).fold(0, |bits, pins| bits | pins.bits())
+    }
+
+    fn pins(&self) -> Vec<u64> {
+        let mut pins = vec![self.oe, self.clk, self.lat, self.a, self.b, self.c, self.d];
+        if let Some(e) = self.e {
+            pins.push(e);
+        }
+        for chain in &self.chains {
+            pins.extend_from_slice(&[chain.r1, chain.g1, chain.b1, chain.r2, chain.g2, chain.b2]);
+        }
+        pins
+    }
+}
+
+#[test]
+fn presets_are_valid_test() {
+    for name in &["adafruit-hat", "adafruit-hat-pwm", "regular", "classic"] {
+        let mapping = PinMapping::by_name(name).unwrap();
+        assert_eq!(Ok(()), mapping.validate(), "Preset {} is invalid", name);
+    }
+    assert!(PinMapping::by_name("custom").is_none());
+}
+
+#[test]
+fn validate_rejects_duplicate_pins_test() {
+    let mut mapping = PinMapping::adafruit_hat();
+    mapping.clk = mapping.lat;
+    assert!(mapping.validate().is_err());
+}
+
+#[test]
+fn validate_rejects_out_of_range_pins_test() {
+    let mut mapping = PinMapping::regular();
+    mapping.chains[2].b2 = 28;
+    assert!(mapping.validate().is_err());
+}
+
+#[test]
+fn check_panel_test() {
+    let config = PanelConfig::new(64, 64, 32).unwrap();
+    assert!(PinMapping::adafruit_hat().check_panel(&config).is_ok());
+    assert!(PinMapping::classic().check_panel(&config).is_err());
+    assert!(PinMapping::adafruit_hat().check_panel(&PanelConfig::default().chained(1, 2).unwrap()).is_err());
+    assert!(PinMapping::regular().check_panel(&PanelConfig::default().chained(1, 3).unwrap()).is_ok());
+}
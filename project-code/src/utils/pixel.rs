@@ -1,6 +1,4 @@
-use super::gpio_driver::COLOR_DEPTH;
-
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Pixel {
     pub r: u16,
     pub g: u16,
@@ -24,12 +22,4 @@ impl Pixel {
         };
         pixel
     }
-
-    //TODO to Full Color!
-    /*
-    pub fn toFullColor(self: &mut Pixel){
-        self.r = self.r * ((1<< COLOR_DEPTH)-1)/255;
-        self.g = self.g * ((1<< COLOR_DEPTH)-1)/255;
-        self.b = self.b * ((1<< COLOR_DEPTH)-1)/255;
-    }*/
 }
\ No newline at end of file
@@ -0,0 +1,130 @@
+use super::panel_config::PanelConfig;
+
+/// Maps positions on the logical canvas that callers draw on to positions on
+/// the physical canvas, which is `chain_length` panels wide and `parallel`
+/// panels high in the order the panels are wired.
+pub trait PixelMapper {
+    /// Whether the panels can be arranged this way at all.
+    fn check(&self, _config: &PanelConfig) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn logical_size(&self, config: &PanelConfig) -> (usize, usize);
+    fn map(&self, config: &PanelConfig, x: usize, y: usize) -> (usize, usize);
+}
+
+pub struct IdentityMapper;
+
+impl PixelMapper for IdentityMapper {
+    fn logical_size(&self, config: &PanelConfig) -> (usize, usize) {
+        (config.canvas_width(), config.canvas_height())
+    }
+
+    fn map(&self, _config: &PanelConfig, x: usize, y: usize) -> (usize, usize) {
+        (x, y)
+    }
+}
+
+/// Whole canvas rotated by 180 degrees.
+pub struct UpsideDownMapper;
+
+impl PixelMapper for UpsideDownMapper {
+    fn logical_size(&self, config: &PanelConfig) -> (usize, usize) {
+        (config.canvas_width(), config.canvas_height())
+    }
+
+    fn map(&self, config: &PanelConfig, x: usize, y: usize) -> (usize, usize) {
+        (config.canvas_width() - 1 - x, config.canvas_height() - 1 - y)
+    }
+}
+
+/// Each chain is folded into `rows` rows of panels. The chain starts at the
+/// top left panel and runs to the right; every odd row runs back to the left
+/// with its panels mounted upside down.
+pub struct SnakeMapper {
+    pub rows: usize,
+}
+
+impl PixelMapper for SnakeMapper {
+    fn check(&self, config: &PanelConfig) -> Result<(), String> {
+        if self.rows == 0 || !config.chain_length.is_multiple_of(self.rows) {
+            return Err(format!("Chain length {} can not be folded into {} rows", config.chain_length, self.rows));
+        }
+        Ok(())
+    }
+
+    fn logical_size(&self, config: &PanelConfig) -> (usize, usize) {
+        (config.canvas_width() / self.rows, config.canvas_height() * self.rows)
+    }
+
+    fn map(&self, config: &PanelConfig, x: usize, y: usize) -> (usize, usize) {
+        let panels_per_row = config.chain_length / self.rows;
+        let chain_height = config.height * self.rows;
+
+        let chain = y / chain_height;
+        let panel_row = (y % chain_height) / config.height;
+        let panel_col = x / config.width;
+        let panel_x = x % config.width;
+        let panel_y = y % config.height;
+
+        if panel_row.is_multiple_of(2) {
+            let index = panel_row * panels_per_row + panel_col;
+            (index * config.width + panel_x, chain * config.height + panel_y)
+        } else {
+            let index = panel_row * panels_per_row + (panels_per_row - 1 - panel_col);
+            (index * config.width + config.width - 1 - panel_x, chain * config.height + config.height - 1 - panel_y)
+        }
+    }
+}
+
+pub fn pixel_mapper_by_name(name: &str) -> Option<Box<dyn PixelMapper>> {
+    if name == "identity" {
+        return Some(Box::new(IdentityMapper));
+    }
+    if name == "upside-down" {
+        return Some(Box::new(UpsideDownMapper));
+    }
+    if let Some(rows) = name.strip_prefix("snake:") {
+        return match rows.parse::<usize>() {
+            Ok(rows) if rows > 0 => Some(Box::new(SnakeMapper { rows })),
+            _ => None,
+        };
+    }
+    None
+}
+
+#[test]
+fn upside_down_mapper_test() {
+    let config = PanelConfig::default().chained(2, 1).unwrap();
+    assert_eq!((63, 15), UpsideDownMapper.map(&config, 0, 0));
+    assert_eq!((0, 0), UpsideDownMapper.map(&config, 63, 15));
+}
+
+#[test]
+fn snake_mapper_test() {
+    let config = PanelConfig::default().chained(4, 1).unwrap();
+    let mapper = SnakeMapper { rows: 2 };
+    assert_eq!((64, 32), mapper.logical_size(&config));
+
+    assert_eq!((0, 0), mapper.map(&config, 0, 0));
+    assert_eq!((63, 15), mapper.map(&config, 63, 15));
+    // Bottom row of panels: logical bottom right is the first pixel of panel 2, turned around.
+    assert_eq!((64 + 31, 15), mapper.map(&config, 63 - 31, 16));
+    assert_eq!((64, 0), mapper.map(&config, 63, 31));
+    assert_eq!((127, 15), mapper.map(&config, 0, 16));
+}
+
+#[test]
+fn snake_mapper_check_test() {
+    let mapper = SnakeMapper { rows: 2 };
+    assert!(mapper.check(&PanelConfig::default().chained(4, 1).unwrap()).is_ok());
+    assert!(mapper.check(&PanelConfig::default()).is_err());
+    assert!(mapper.check(&PanelConfig::default().chained(3, 1).unwrap()).is_err());
+}
+
+#[test]
+fn pixel_mapper_by_name_test() {
+    assert!(pixel_mapper_by_name("snake:2").is_some());
+    assert!(pixel_mapper_by_name("snake:0").is_none());
+    assert!(pixel_mapper_by_name("sideways").is_none());
+}
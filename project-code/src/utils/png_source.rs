@@ -0,0 +1,73 @@
+use std::io::Read;
+
+use png;
+use png::ColorType;
+
+use super::image::Image;
+use super::image_source::{blend_onto_black, ImageFormat, ImageSource, SourceError};
+use super::pixel::Pixel;
+
+pub struct PngSource<R: Read> {
+    reader: Option<R>,
+}
+
+impl<R: Read> PngSource<R> {
+    pub fn new(reader: R) -> PngSource<R> {
+        PngSource {
+            reader: Some(reader),
+        }
+    }
+}
+
+impl<R: Read> ImageSource for PngSource<R> {
+    // A PNG holds a single image.
+    fn next_image(&mut self) -> Result<Option<Image>, SourceError> {
+        let reader = match self.reader.take() {
+            Some(reader) => reader,
+            None => return Ok(None),
+        };
+        decode_png_image(reader).map(Some)
+    }
+}
+
+// The decoder expands palettes and low bit depths and strips 16-bit samples,
+// so every sample comes out as a byte.
+fn decode_png_image<R: Read>(reader: R) -> Result<Image, SourceError> {
+    let decode_error = |why: png::DecodingError| SourceError::Decode(ImageFormat::Png, why.to_string());
+    let (info, mut reader) = png::Decoder::new(reader).read_info().map_err(decode_error)?;
+    let mut buffer = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buffer).map_err(decode_error)?;
+
+    let channels = match info.color_type {
+        ColorType::Grayscale => 1,
+        ColorType::GrayscaleAlpha => 2,
+        ColorType::RGB => 3,
+        ColorType::RGBA => 4,
+        ColorType::Indexed => return Err(SourceError::Decode(ImageFormat::Png, String::from("palette was not expanded"))),
+    };
+
+    let pixels = buffer.chunks(info.line_size)
+        .take(info.height as usize)
+        .map(|line| {
+            line.chunks(channels)
+                .take(info.width as usize)
+                .map(build_pixel)
+                .collect()
+        })
+        .collect();
+
+    Ok(Image {
+        width: info.width,
+        height: info.height,
+        pixels,
+    })
+}
+
+fn build_pixel(sample: &[u8]) -> Pixel {
+    match sample.len() {
+        1 => Pixel::new_colored_pixel(sample[0] as u16, sample[0] as u16, sample[0] as u16),
+        2 => blend_onto_black(sample[0], sample[0], sample[0], sample[1]),
+        3 => Pixel::new_colored_pixel(sample[0] as u16, sample[1] as u16, sample[2] as u16),
+        _ => blend_onto_black(sample[0], sample[1], sample[2], sample[3]),
+    }
+}
@@ -0,0 +1,128 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::mem;
+
+use libc;
+use nix;
+use nix::sched::{sched_setaffinity, CpuSet};
+use nix::sys::mman::{mlockall, MlockAllFlags};
+use nix::unistd::Pid;
+
+/// Opt-in measures against the refresh loop being preempted, which shows as
+/// flicker. Everything is off by default.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RealtimeSettings {
+    /// SCHED_FIFO priority, 1-99.
+    pub priority: Option<i32>,
+    /// Core to pin the refresh thread to, ideally one kept free of other
+    /// tasks with the isolcpus kernel parameter.
+    pub cpu: Option<usize>,
+    /// Keep all memory of the process resident so the refresh never waits
+    /// for a page fault.
+    pub lock_memory: bool,
+}
+
+impl RealtimeSettings {
+    /// Applies the settings to the calling thread. Locking memory applies to
+    /// the whole process.
+    pub fn apply_to_current_thread(&self) -> Result<(), RealtimeError> {
+        if self.lock_memory {
+            mlockall(MlockAllFlags::MCL_CURRENT | MlockAllFlags::MCL_FUTURE)
+                .map_err(|why| RealtimeError::LockMemory(io_error(why)))?;
+        }
+        if let Some(cpu) = self.cpu {
+            pin_current_thread(cpu)?;
+        }
+        if let Some(priority) = self.priority {
+            set_fifo_priority(priority)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum RealtimeError {
+    Priority { priority: i32, source: io::Error },
+    Affinity { cpu: usize, source: io::Error },
+    LockMemory(io::Error),
+}
+
+impl fmt::Display for RealtimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RealtimeError::Priority { priority, source } if is_permission_error(source) =>
+                write!(f, "Permission denied setting SCHED_FIFO priority {}: this needs root or the CAP_SYS_NICE capability", priority),
+            RealtimeError::Priority { priority, source } =>
+                write!(f, "Could not set SCHED_FIFO priority {}: {}", priority, source),
+            RealtimeError::Affinity { cpu, source } if is_permission_error(source) =>
+                write!(f, "Permission denied pinning the refresh thread to CPU {}: this needs root or the CAP_SYS_NICE capability", cpu),
+            RealtimeError::Affinity { cpu, source } if source.raw_os_error() == Some(libc::EINVAL) =>
+                write!(f, "Could not pin the refresh thread to CPU {}: no such CPU is available to this process", cpu),
+            RealtimeError::Affinity { cpu, source } =>
+                write!(f, "Could not pin the refresh thread to CPU {}: {}", cpu, source),
+            RealtimeError::LockMemory(source) if is_permission_error(source) || source.raw_os_error() == Some(libc::ENOMEM) =>
+                write!(f, "Could not lock the process memory: this needs root, the CAP_IPC_LOCK capability or a higher RLIMIT_MEMLOCK"),
+            RealtimeError::LockMemory(source) =>
+                write!(f, "Could not lock the process memory: {}", source),
+        }
+    }
+}
+
+impl Error for RealtimeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RealtimeError::Priority { source, .. } => Some(source),
+            RealtimeError::Affinity { source, .. } => Some(source),
+            RealtimeError::LockMemory(source) => Some(source),
+        }
+    }
+}
+
+fn set_fifo_priority(priority: i32) -> Result<(), RealtimeError> {
+    let mut param: libc::sched_param = unsafe { mem::zeroed() };
+    param.sched_priority = priority;
+    // Returns the error number instead of setting errno.
+    match unsafe { libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) } {
+        0 => Ok(()),
+        errno => Err(RealtimeError::Priority { priority, source: io::Error::from_raw_os_error(errno) }),
+    }
+}
+
+fn pin_current_thread(cpu: usize) -> Result<(), RealtimeError> {
+    let mut cpu_set = CpuSet::new();
+    cpu_set.set(cpu)
+        .and_then(|_| sched_setaffinity(Pid::from_raw(0), &cpu_set))
+        .map_err(|why| RealtimeError::Affinity { cpu, source: io_error(why) })
+}
+
+fn io_error(why: nix::Error) -> io::Error {
+    match why {
+        nix::Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
+        _ => io::Error::from_raw_os_error(libc::EINVAL),
+    }
+}
+
+fn is_permission_error(why: &io::Error) -> bool {
+    why.raw_os_error() == Some(libc::EPERM) || why.kind() == io::ErrorKind::PermissionDenied
+}
+
+#[test]
+fn realtime_error_names_capability_test() {
+    let denied = || io::Error::from_raw_os_error(libc::EPERM);
+    assert!(RealtimeError::Priority { priority: 50, source: denied() }.to_string().contains("CAP_SYS_NICE"));
+    assert!(RealtimeError::Affinity { cpu: 3, source: denied() }.to_string().contains("CAP_SYS_NICE"));
+    assert!(RealtimeError::LockMemory(denied()).to_string().contains("CAP_IPC_LOCK"));
+    assert!(RealtimeError::Affinity { cpu: 64, source: io::Error::from_raw_os_error(libc::EINVAL) }.to_string().contains("no such CPU"));
+}
+
+#[test]
+fn pin_current_thread_test() {
+    std::thread::spawn(|| {
+        // CpuSet only has room for so many CPUs.
+        match pin_current_thread(100_000) {
+            Err(RealtimeError::Affinity { cpu: 100_000, .. }) => {},
+            _ => panic!("Expected an affinity error"),
+        }
+    }).join().unwrap();
+}
@@ -0,0 +1,297 @@
+#[cfg(feature = "gif")]
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "gif")]
+use gif;
+#[cfg(feature = "gif")]
+use gif::SetParameter;
+
+use super::backend::Backend;
+use super::frame::Frame;
+use super::image::Image;
+#[cfg(test)]
+use super::file_reader::read_ppm_file;
+#[cfg(test)]
+use super::panel_config::PanelConfig;
+#[cfg(test)]
+use super::pixel::Pixel;
+#[cfg(test)]
+use super::pixel_mapper::IdentityMapper;
+
+// Without a panel to wait for the render loops would spin.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(1);
+#[cfg(feature = "gif")]
+const LAST_GIF_FRAME_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RecordFormat {
+    /// A directory of numbered binary PPM files.
+    Ppm,
+    /// An endlessly looping GIF, with the time between frames as delays.
+    Gif,
+    /// For every frame the microseconds since the start as a little-endian
+    /// u64, followed by three bytes per pixel, row by row.
+    Raw,
+}
+
+impl RecordFormat {
+    pub fn from_name(name: &str) -> Option<RecordFormat> {
+        match name {
+            "ppm" => Some(RecordFormat::Ppm),
+            "gif" => Some(RecordFormat::Gif),
+            "raw" => Some(RecordFormat::Raw),
+            _ => None,
+        }
+    }
+}
+
+enum Sink {
+    Ppm(PathBuf),
+    // The encoder needs the size of the first frame, and the delay of a frame
+    // is only known when the next one arrives.
+    #[cfg(feature = "gif")]
+    Gif {
+        path: PathBuf,
+        encoder: Option<gif::Encoder<BufWriter<File>>>,
+        pending: Option<(Image, Duration)>,
+    },
+    Raw(BufWriter<File>),
+}
+
+/// Writes every frame that is loaded to disk instead of showing it.
+pub struct RecordingMatrix {
+    sink: Sink,
+    start: Instant,
+    frame_count: usize,
+    failed: bool,
+}
+
+impl RecordingMatrix {
+    pub fn create(format: RecordFormat, path: &Path) -> io::Result<RecordingMatrix> {
+        let sink = match format {
+            RecordFormat::Ppm => {
+                fs::create_dir_all(path)?;
+                Sink::Ppm(path.to_path_buf())
+            }
+            #[cfg(feature = "gif")]
+            RecordFormat::Gif => Sink::Gif { path: path.to_path_buf(), encoder: None, pending: None },
+            #[cfg(not(feature = "gif"))]
+            RecordFormat::Gif => return Err(io::Error::new(io::ErrorKind::Other, "Support for GIF was not compiled in")),
+            RecordFormat::Raw => Sink::Raw(BufWriter::new(File::create(path)?)),
+        };
+        Ok(RecordingMatrix {
+            sink,
+            start: Instant::now(),
+            frame_count: 0,
+            failed: false,
+        })
+    }
+
+    /// `timestamp` is the time since the recording started.
+    pub fn record(&mut self, image: Image, timestamp: Duration) -> io::Result<()> {
+        match &mut self.sink {
+            Sink::Ppm(directory) => {
+                let mut out = BufWriter::new(File::create(directory.join(format!("frame_{:06}.ppm", self.frame_count)))?);
+                image.write_ppm(&mut out)?;
+                out.flush()?;
+            }
+            #[cfg(feature = "gif")]
+            Sink::Gif { path, encoder, pending } => {
+                if let Some((previous, previous_timestamp)) = pending.take() {
+                    write_gif_frame(path, encoder, &previous, timestamp - previous_timestamp)?;
+                }
+                *pending = Some((image, timestamp));
+            }
+            Sink::Raw(out) => {
+                let micros = timestamp.as_secs() * 1_000_000 + timestamp.subsec_micros() as u64;
+                out.write_all(&micros.to_le_bytes())?;
+                out.write_all(&image.to_rgb_bytes())?;
+            }
+        }
+        self.frame_count += 1;
+        Ok(())
+    }
+
+    /// Writes out what is still buffered. The GIF is complete once the
+    /// recorder is dropped.
+    pub fn finish(&mut self) -> io::Result<()> {
+        match &mut self.sink {
+            Sink::Ppm(_) => Ok(()),
+            #[cfg(feature = "gif")]
+            Sink::Gif { path, encoder, pending } => match pending.take() {
+                Some((image, _timestamp)) => write_gif_frame(path, encoder, &image, LAST_GIF_FRAME_DELAY),
+                None => Ok(()),
+            },
+            Sink::Raw(out) => out.flush(),
+        }
+    }
+
+    // Recording stops at the first error, there is no one to return it to.
+    fn check(&mut self, result: io::Result<()>) {
+        if let Err(why) = result {
+            eprintln!("Could not record frame {}: {}", self.frame_count, why);
+            self.failed = true;
+        }
+    }
+}
+
+impl Backend for RecordingMatrix {
+    fn load_frame(&mut self, frame: &Frame) {
+        if !self.failed {
+            let result = self.record(frame.to_image(), self.start.elapsed());
+            self.check(result);
+        }
+    }
+
+    fn refresh(&mut self, _frame: &Frame) {
+        thread::sleep(REFRESH_INTERVAL);
+    }
+
+    fn blank(&mut self) {
+        if !self.failed {
+            let result = self.finish();
+            self.check(result);
+        }
+    }
+}
+
+#[cfg(feature = "gif")]
+fn write_gif_frame(path: &Path, encoder: &mut Option<gif::Encoder<BufWriter<File>>>, image: &Image, delay: Duration) -> io::Result<()> {
+    if encoder.is_none() {
+        let mut new_encoder = gif::Encoder::new(BufWriter::new(File::create(path)?), image.width as u16, image.height as u16, &[])?;
+        new_encoder.set(gif::Repeat::Infinite)?;
+        *encoder = Some(new_encoder);
+    }
+    let mut frame = gif_frame(image);
+    // GIF delays are in hundredths of a second.
+    let centiseconds = (delay.as_secs() * 100 + (delay.subsec_millis() as u64 + 5) / 10).min(0xFFFF);
+    frame.delay = centiseconds as u16;
+    encoder.as_mut().unwrap().write_frame(&frame)
+}
+
+// Panels mostly show a handful of colors, which are kept exact. Only frames
+// with more than 256 colors are quantized.
+#[cfg(feature = "gif")]
+fn gif_frame(image: &Image) -> gif::Frame<'static> {
+    let (width, height) = (image.width as u16, image.height as u16);
+    let rgb = image.to_rgb_bytes();
+    let mut palette: Vec<u8> = Vec::new();
+    let mut palette_indices: HashMap<&[u8], u8> = HashMap::new();
+    let mut pixels: Vec<u8> = Vec::with_capacity(rgb.len() / 3);
+    for color in rgb.chunks(3) {
+        let index = match palette_indices.get(color) {
+            Some(index) => *index,
+            None if palette_indices.len() < 256 => {
+                let index = palette_indices.len() as u8;
+                palette_indices.insert(color, index);
+                palette.extend_from_slice(color);
+                index
+            }
+            None => return gif::Frame::from_rgb(width, height, &rgb),
+        };
+        pixels.push(index);
+    }
+    gif::Frame::from_palette_pixels(width, height, &pixels, &palette, None)
+}
+
+#[cfg(test)]
+fn recording_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("project-code-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&path);
+    let _ = fs::remove_file(&path);
+    path
+}
+
+#[cfg(test)]
+fn assert_golden(recorded: &Path, golden: &str) {
+    let recorded = read_ppm_file(recorded).unwrap();
+    let expected = read_ppm_file(Path::new(golden)).unwrap();
+    assert_eq!((expected.width, expected.height), (recorded.width, recorded.height));
+    for (row, (expected_row, recorded_row)) in expected.pixels.iter().zip(recorded.pixels.iter()).enumerate() {
+        assert_eq!(expected_row, recorded_row, "Row {} differs from {}", row, golden);
+    }
+}
+
+#[test]
+fn record_scrolling_image_golden_test() {
+    let path = recording_path("scroll");
+    let mut recorder = RecordingMatrix::create(RecordFormat::Ppm, &path).unwrap();
+    let image = read_ppm_file(Path::new("resources/netpbm/pixmap_binary.ppm")).unwrap();
+    let mut frame = Frame::with_mapper(&PanelConfig::default(), &IdentityMapper).unwrap();
+    for _tick in 0..3 {
+        frame.next_image_frame(&image);
+        recorder.load_frame(&frame);
+    }
+    recorder.blank();
+
+    assert!(!path.join("frame_000003.ppm").exists());
+    assert_golden(&path.join("frame_000002.ppm"), "resources/golden/scroll_tick_2.ppm");
+    fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn record_snake_start_golden_test() {
+    use super::super::snake_game::game::Game;
+
+    let path = recording_path("snake");
+    let mut recorder = RecordingMatrix::create(RecordFormat::Ppm, &path).unwrap();
+    let mut frame = Frame::with_mapper(&PanelConfig::default(), &IdentityMapper).unwrap();
+    Game::new(frame.width, frame.height).draw(&mut frame);
+    recorder.load_frame(&frame);
+
+    assert_golden(&path.join("frame_000000.ppm"), "resources/golden/snake_start.ppm");
+    fs::remove_dir_all(&path).unwrap();
+}
+
+#[test]
+fn record_raw_stream_test() {
+    let path = recording_path("raw.rgb");
+    let mut recorder = RecordingMatrix::create(RecordFormat::Raw, &path).unwrap();
+    let mut frame = Frame::with_mapper(&PanelConfig::default(), &IdentityMapper).unwrap();
+    frame.pixels[0][0] = Pixel::new_colored_pixel(255, 0, 0);
+    recorder.record(frame.to_image(), Duration::from_millis(0)).unwrap();
+    recorder.record(frame.to_image(), Duration::from_micros(1500)).unwrap();
+    recorder.finish().unwrap();
+
+    let bytes = fs::read(&path).unwrap();
+    let frame_size = 8 + 32 * 16 * 3;
+    assert_eq!(2 * frame_size, bytes.len());
+    assert_eq!(&[255, 0, 0], &bytes[8..11]);
+    assert_eq!(&1500u64.to_le_bytes(), &bytes[frame_size..frame_size + 8]);
+    fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "gif")]
+#[test]
+fn record_gif_test() {
+    use super::gif_source::GifSource;
+    use super::image_source::ImageSource;
+
+    let path = recording_path("recording.gif");
+    let mut recorder = RecordingMatrix::create(RecordFormat::Gif, &path).unwrap();
+    let mut frame = Frame::with_mapper(&PanelConfig::default(), &IdentityMapper).unwrap();
+    frame.pixels[0][0] = Pixel::new_colored_pixel(255, 0, 0);
+    recorder.record(frame.to_image(), Duration::from_millis(0)).unwrap();
+    frame.pixels[0][0] = Pixel::new_colored_pixel(0, 0, 255);
+    recorder.record(frame.to_image(), Duration::from_millis(250)).unwrap();
+    recorder.finish().unwrap();
+    drop(recorder);
+
+    let mut source = GifSource::new(File::open(&path).unwrap()).unwrap();
+    let first = source.next_image().unwrap().unwrap();
+    assert_eq!(Some(Duration::from_millis(250)), source.frame_delay());
+    assert_eq!((32, 16), (first.width, first.height));
+    let second = source.next_image().unwrap().unwrap();
+    assert_eq!(Some(LAST_GIF_FRAME_DELAY), source.frame_delay());
+    assert_eq!(Pixel::new_colored_pixel(255, 0, 0), first.pixels[0][0]);
+    assert_eq!(Pixel::new_colored_pixel(0, 0, 255), second.pixels[0][0]);
+    assert!(source.next_image().unwrap().is_none());
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,197 @@
+use std;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+use mmap::MemoryMap;
+
+use super::gpio_driver::{mmap_bcm_register, mmap_gpio_register, DriverError, REGISTER_BLOCK_SIZE};
+#[cfg(test)]
+use super::backend::GpioMatrix;
+#[cfg(test)]
+use super::gpio_driver::{GPCLR0, GPFSEL0, GPIO, GPLEV0, GPSET0};
+#[cfg(test)]
+use super::panel_config::PanelConfig;
+#[cfg(test)]
+use super::pin_mapping::PinMapping;
+#[cfg(test)]
+use super::time::{FakeClock, Timer};
+
+const REGISTER_WORDS: usize = REGISTER_BLOCK_SIZE as usize / 4;
+
+pub trait RegisterBlock {
+    fn read(&self, word: usize) -> u32;
+    fn write(&mut self, word: usize, value: u32);
+}
+
+pub struct MmapRegisterBlock {
+    _map: MemoryMap,
+    registers: *mut u32,
+}
+
+impl MmapRegisterBlock {
+    pub fn new(peripheral_base: u64, register_offset: usize) -> Result<MmapRegisterBlock, DriverError> {
+        mmap_bcm_register(peripheral_base, register_offset).map(MmapRegisterBlock::from_map)
+    }
+
+    pub fn gpio(peripheral_base: u64) -> Result<MmapRegisterBlock, DriverError> {
+        mmap_gpio_register(peripheral_base).map(MmapRegisterBlock::from_map)
+    }
+
+    fn from_map(map: MemoryMap) -> MmapRegisterBlock {
+        MmapRegisterBlock {
+            registers: map.data() as *mut u32,
+            _map: map,
+        }
+    }
+}
+
+// The mapping stays valid until the block is dropped, whichever thread does so.
+unsafe impl Send for MmapRegisterBlock {}
+
+impl RegisterBlock for MmapRegisterBlock {
+    fn read(&self, word: usize) -> u32 {
+        debug_assert!(word < REGISTER_WORDS);
+        unsafe { std::ptr::read_volatile(self.registers.add(word)) }
+    }
+
+    fn write(&mut self, word: usize, value: u32) {
+        debug_assert!(word < REGISTER_WORDS);
+        unsafe { std::ptr::write_volatile(self.registers.add(word), value) }
+    }
+}
+
+impl<R: RegisterBlock + ?Sized> RegisterBlock for Box<R> {
+    fn read(&self, word: usize) -> u32 {
+        (**self).read(word)
+    }
+
+    fn write(&mut self, word: usize, value: u32) {
+        (**self).write(word, value)
+    }
+}
+
+#[cfg(test)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RegisterWrite {
+    pub word: usize,
+    pub value: u32,
+}
+
+/// Heap-backed register block that behaves like the GPIO block: writes to the
+/// set/clear words update the level word, and every write to the
+/// function-select, set or clear words is recorded in order. Clones share
+/// the registers, so a test can keep one to look at what the driver wrote.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct FakeRegisterBlock {
+    state: Arc<Mutex<FakeRegisters>>,
+}
+
+#[cfg(test)]
+struct FakeRegisters {
+    words: Vec<u32>,
+    writes: Vec<RegisterWrite>,
+}
+
+#[cfg(test)]
+impl FakeRegisterBlock {
+    pub fn new() -> FakeRegisterBlock {
+        FakeRegisterBlock {
+            state: Arc::new(Mutex::new(FakeRegisters {
+                words: vec![0; REGISTER_WORDS],
+                writes: Vec::new(),
+            })),
+        }
+    }
+
+    pub fn writes(&self) -> Vec<RegisterWrite> {
+        self.state.lock().unwrap().writes.clone()
+    }
+
+    pub fn clear_writes(&self) {
+        self.state.lock().unwrap().writes.clear();
+    }
+
+    pub fn set_word(&self, word: usize, value: u32) {
+        self.state.lock().unwrap().words[word] = value;
+    }
+}
+
+#[cfg(test)]
+impl RegisterBlock for FakeRegisterBlock {
+    fn read(&self, word: usize) -> u32 {
+        self.state.lock().unwrap().words[word]
+    }
+
+    fn write(&mut self, word: usize, value: u32) {
+        let mut state = self.state.lock().unwrap();
+        match word {
+            GPSET0 => state.words[GPLEV0] |= value,
+            GPCLR0 => state.words[GPLEV0] &= !value,
+            _ => state.words[word] = value,
+        }
+        if (GPFSEL0..GPFSEL0 + 6).contains(&word) || word == GPSET0 || word == GPCLR0 {
+            state.writes.push(RegisterWrite { word, value });
+        }
+    }
+}
+
+/// A panel on fake registers, which is what most driver tests start from.
+#[cfg(test)]
+pub struct FakePanel {
+    pub pins: PinMapping,
+    pub config: PanelConfig,
+    pub registers: FakeRegisterBlock,
+}
+
+#[cfg(test)]
+impl FakePanel {
+    /// A default panel on the Adafruit HAT pins.
+    pub fn new() -> FakePanel {
+        FakePanel::with_config(PanelConfig::default(), PinMapping::adafruit_hat())
+    }
+
+    pub fn with_config(config: PanelConfig, pins: PinMapping) -> FakePanel {
+        FakePanel {
+            pins,
+            config,
+            registers: FakeRegisterBlock::new(),
+        }
+    }
+
+    /// A driver without slowdown that writes to `registers`.
+    pub fn gpio(&self) -> GPIO<FakeRegisterBlock> {
+        GPIO::with_registers(self.registers.clone(), 0, &self.config, &self.pins).unwrap()
+    }
+
+    pub fn timer(&self) -> Timer<FakeClock> {
+        Timer::with_clock(FakeClock::new(0))
+    }
+
+    pub fn matrix(&self) -> GpioMatrix<FakeRegisterBlock, FakeClock> {
+        GpioMatrix::new(self.gpio(), self.timer())
+    }
+}
+
+#[test]
+fn fake_register_block_tracks_levels_test() {
+    let mut registers = FakeRegisterBlock::new();
+    let handle = registers.clone();
+    registers.write(GPSET0, 0b1010);
+    registers.write(GPCLR0, 0b0010);
+    registers.write(GPSET0, 0b0001);
+
+    assert_eq!(0b1001, handle.read(GPLEV0), "Level word does not follow set/clear writes");
+    assert_eq!(3, handle.writes().len(), "Not every write was recorded");
+    assert_eq!(RegisterWrite { word: GPCLR0, value: 0b0010 }, handle.writes()[1]);
+}
+
+#[test]
+fn fake_register_block_ignores_other_words_test() {
+    let mut registers = FakeRegisterBlock::new();
+    registers.write(37, 42);
+    registers.write(GPFSEL0 + 2, 1 << 3);
+
+    assert_eq!(42, registers.read(37));
+    assert_eq!(vec![RegisterWrite { word: GPFSEL0 + 2, value: 1 << 3 }], registers.writes());
+}
@@ -0,0 +1,227 @@
+use super::image::Image;
+use super::pixel::Pixel;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+    /// Averages every source pixel a target pixel covers. The best choice for
+    /// shrinking large images, the same as nearest when enlarging.
+    Box,
+}
+
+impl Filter {
+    pub fn from_name(name: &str) -> Option<Filter> {
+        match name {
+            "nearest" => Some(Filter::Nearest),
+            "bilinear" => Some(Filter::Bilinear),
+            "box" => Some(Filter::Box),
+            _ => None,
+        }
+    }
+}
+
+/// How an image is made to match the size of the panel.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FitPolicy {
+    /// Scale to fit inside the panel keeping the aspect ratio, with black bars.
+    Fit,
+    /// Scale to cover the whole panel keeping the aspect ratio, cropping the edges.
+    Fill,
+    /// Scale to the panel size, ignoring the aspect ratio.
+    Stretch,
+    /// Keep the original size, centered, cropping or padding with black.
+    Center,
+}
+
+impl FitPolicy {
+    pub fn from_name(name: &str) -> Option<FitPolicy> {
+        match name {
+            "fit" => Some(FitPolicy::Fit),
+            "fill" => Some(FitPolicy::Fill),
+            "stretch" => Some(FitPolicy::Stretch),
+            "center" => Some(FitPolicy::Center),
+            _ => None,
+        }
+    }
+}
+
+pub fn fit_image(image: &Image, width: usize, height: usize, policy: FitPolicy, filter: Filter) -> Image {
+    let (image_width, image_height) = (image.width as usize, image.height as usize);
+    if image_width == 0 || image_height == 0 {
+        return black_image(width, height);
+    }
+
+    let (scaled_width, scaled_height) = match policy {
+        FitPolicy::Stretch => (width, height),
+        FitPolicy::Center => (image_width, image_height),
+        FitPolicy::Fit | FitPolicy::Fill => {
+            // Compare width / image_width with height / image_height without rounding.
+            let width_limited = width * image_height <= height * image_width;
+            if width_limited == (policy == FitPolicy::Fit) {
+                (width, (image_height * width + image_width / 2) / image_width)
+            } else {
+                ((image_width * height + image_height / 2) / image_height, height)
+            }
+        }
+    };
+    let scaled = if (scaled_width, scaled_height) == (image_width, image_height) {
+        image.clone()
+    } else {
+        resample(image, scaled_width.max(1), scaled_height.max(1), filter)
+    };
+
+    center_on(&scaled, width, height)
+}
+
+pub fn resample(image: &Image, width: usize, height: usize, filter: Filter) -> Image {
+    let pixels = (0..height)
+        .map(|row| {
+            (0..width)
+                .map(|col| match filter {
+                    Filter::Nearest => nearest_pixel(image, width, height, col, row),
+                    Filter::Bilinear => bilinear_pixel(image, width, height, col, row),
+                    Filter::Box => box_pixel(image, width, height, col, row),
+                })
+                .collect()
+        })
+        .collect();
+
+    Image {
+        width: width as u32,
+        height: height as u32,
+        pixels,
+    }
+}
+
+// Places the image in the middle of a black canvas, cropping what does not fit.
+fn center_on(image: &Image, width: usize, height: usize) -> Image {
+    let mut result = black_image(width, height);
+    let offset_x = width as isize / 2 - image.width as isize / 2;
+    let offset_y = height as isize / 2 - image.height as isize / 2;
+
+    for (row, pixels) in result.pixels.iter_mut().enumerate() {
+        for (col, pixel) in pixels.iter_mut().enumerate() {
+            let (x, y) = (col as isize - offset_x, row as isize - offset_y);
+            if x >= 0 && y >= 0 && (x as u32) < image.width && (y as u32) < image.height {
+                *pixel = image.pixels[y as usize][x as usize];
+            }
+        }
+    }
+    result
+}
+
+fn black_image(width: usize, height: usize) -> Image {
+    Image {
+        width: width as u32,
+        height: height as u32,
+        pixels: vec![vec![Pixel::new(); width]; height],
+    }
+}
+
+fn nearest_pixel(image: &Image, width: usize, height: usize, col: usize, row: usize) -> Pixel {
+    let x = col * image.width as usize / width;
+    let y = row * image.height as usize / height;
+    image.pixels[y][x]
+}
+
+fn bilinear_pixel(image: &Image, width: usize, height: usize, col: usize, row: usize) -> Pixel {
+    // Map pixel centers onto each other.
+    let source_position = |target: usize, target_size: usize, source_size: u32| -> (usize, usize, f32) {
+        let position = ((target as f32 + 0.5) * source_size as f32 / target_size as f32 - 0.5)
+            .max(0.0)
+            .min(source_size as f32 - 1.0);
+        let low = position.floor() as usize;
+        let high = (low + 1).min(source_size as usize - 1);
+        (low, high, position - low as f32)
+    };
+    let (x0, x1, fx) = source_position(col, width, image.width);
+    let (y0, y1, fy) = source_position(row, height, image.height);
+
+    let interpolate = |channel: fn(&Pixel) -> u16| -> u16 {
+        let sample = |x: usize, y: usize| channel(&image.pixels[y][x]) as f32;
+        let top = sample(x0, y0) * (1.0 - fx) + sample(x1, y0) * fx;
+        let bottom = sample(x0, y1) * (1.0 - fx) + sample(x1, y1) * fx;
+        (top * (1.0 - fy) + bottom * fy).round() as u16
+    };
+    Pixel::new_colored_pixel(interpolate(|pixel| pixel.r), interpolate(|pixel| pixel.g), interpolate(|pixel| pixel.b))
+}
+
+fn box_pixel(image: &Image, width: usize, height: usize, col: usize, row: usize) -> Pixel {
+    let span = |target: usize, target_size: usize, source_size: u32| -> (usize, usize) {
+        let start = target * source_size as usize / target_size;
+        let end = ((target + 1) * source_size as usize).div_ceil(target_size);
+        (start, end.max(start + 1))
+    };
+    let (x_start, x_end) = span(col, width, image.width);
+    let (y_start, y_end) = span(row, height, image.height);
+
+    let (mut r, mut g, mut b) = (0u32, 0u32, 0u32);
+    for pixels in &image.pixels[y_start..y_end] {
+        for pixel in &pixels[x_start..x_end] {
+            r += pixel.r as u32;
+            g += pixel.g as u32;
+            b += pixel.b as u32;
+        }
+    }
+    let count = ((x_end - x_start) * (y_end - y_start)) as u32;
+    Pixel::new_colored_pixel(((r + count / 2) / count) as u16, ((g + count / 2) / count) as u16, ((b + count / 2) / count) as u16)
+}
+
+#[cfg(test)]
+fn gradient_image(width: usize, height: usize) -> Image {
+    Image {
+        width: width as u32,
+        height: height as u32,
+        pixels: (0..height)
+            .map(|row| (0..width).map(|col| Pixel::new_colored_pixel(col as u16 * 10, row as u16 * 10, 0)).collect())
+            .collect(),
+    }
+}
+
+#[test]
+fn resample_filters_test() {
+    let image = gradient_image(4, 2);
+
+    let nearest = resample(&image, 2, 1, Filter::Nearest);
+    assert_eq!(vec![Pixel::new_colored_pixel(0, 0, 0), Pixel::new_colored_pixel(20, 0, 0)], nearest.pixels[0]);
+
+    let boxed = resample(&image, 2, 1, Filter::Box);
+    assert_eq!(vec![Pixel::new_colored_pixel(5, 5, 0), Pixel::new_colored_pixel(25, 5, 0)], boxed.pixels[0]);
+
+    let bilinear = resample(&image, 8, 2, Filter::Bilinear);
+    assert_eq!(Pixel::new_colored_pixel(0, 0, 0), bilinear.pixels[0][0]);
+    assert_eq!(Pixel::new_colored_pixel(13, 10, 0), bilinear.pixels[1][3]);
+    assert_eq!(Pixel::new_colored_pixel(30, 10, 0), bilinear.pixels[1][7]);
+}
+
+#[test]
+fn fit_image_policies_test() {
+    // A wide 8x2 image on a 4x4 panel.
+    let image = gradient_image(8, 2);
+
+    let fit = fit_image(&image, 4, 4, FitPolicy::Fit, Filter::Nearest);
+    assert_eq!((4, 4), (fit.width, fit.height));
+    assert_eq!(Pixel::new(), fit.pixels[0][0]);
+    assert_eq!(Pixel::new_colored_pixel(20, 0, 0), fit.pixels[2][1]);
+    assert_eq!(Pixel::new(), fit.pixels[3][3]);
+
+    let fill = fit_image(&image, 4, 4, FitPolicy::Fill, Filter::Nearest);
+    assert_eq!(Pixel::new_colored_pixel(30, 0, 0), fill.pixels[0][0]);
+    assert_eq!(Pixel::new_colored_pixel(40, 10, 0), fill.pixels[3][3]);
+
+    let stretch = fit_image(&image, 4, 4, FitPolicy::Stretch, Filter::Nearest);
+    assert_eq!(Pixel::new_colored_pixel(60, 10, 0), stretch.pixels[3][3]);
+
+    let center = fit_image(&image, 4, 4, FitPolicy::Center, Filter::Nearest);
+    assert_eq!(Pixel::new(), center.pixels[0][0]);
+    assert_eq!(Pixel::new_colored_pixel(20, 0, 0), center.pixels[1][0]);
+    assert_eq!(Pixel::new_colored_pixel(50, 10, 0), center.pixels[2][3]);
+}
+
+#[test]
+fn fit_image_empty_image_test() {
+    let image = Image { width: 0, height: 0, pixels: vec![] };
+    let fitted = fit_image(&image, 2, 2, FitPolicy::Fit, Filter::Bilinear);
+    assert_eq!(vec![Pixel::new(), Pixel::new()], fitted.pixels[1]);
+}
@@ -0,0 +1,151 @@
+use std::fmt::Write as FmtWrite;
+use std::io;
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+use termion::{clear, color, cursor};
+
+use super::backend::Backend;
+use super::frame::Frame;
+use super::pixel::Pixel;
+#[cfg(test)]
+use super::panel_config::PanelConfig;
+#[cfg(test)]
+use super::pixel_mapper::IdentityMapper;
+
+// Terminals cannot keep up with the refresh rate of a panel, nor is there any
+// need to: only changed frames are drawn.
+const FRAME_INTERVAL: Duration = Duration::from_millis(16);
+const UPPER_HALF_BLOCK: char = '\u{2580}';
+
+/// Simulates the panel in a truecolor terminal. Every character cell shows
+/// two LEDs on top of each other: the upper half block in the foreground
+/// color, the lower half in the background color.
+pub struct TerminalMatrix<W: Write = io::Stdout> {
+    out: W,
+    screen: String,
+    dirty: bool,
+    failed: bool,
+}
+
+impl<W: Write> TerminalMatrix<W> {
+    pub fn new(mut out: W) -> io::Result<TerminalMatrix<W>> {
+        write!(out, "{}{}", clear::All, cursor::Hide)?;
+        Ok(TerminalMatrix {
+            out,
+            screen: String::new(),
+            dirty: false,
+            failed: false,
+        })
+    }
+
+    // Once the terminal is gone, e.g. the pipe it was writing to was closed,
+    // nothing is drawn anymore.
+    fn check(&mut self, result: io::Result<()>) {
+        if let Err(why) = result {
+            eprintln!("Could not write to the terminal: {}", why);
+            self.failed = true;
+        }
+    }
+}
+
+impl<W: Write> Backend for TerminalMatrix<W> {
+    fn load_frame(&mut self, frame: &Frame) {
+        self.screen = render_half_blocks(frame);
+        self.dirty = true;
+    }
+
+    fn refresh(&mut self, _frame: &Frame) {
+        if self.dirty && !self.failed {
+            let result = self.out.write_all(self.screen.as_bytes()).and_then(|_| self.out.flush());
+            self.check(result);
+            self.dirty = false;
+        }
+        thread::sleep(FRAME_INTERVAL);
+    }
+
+    fn blank(&mut self) {
+        if !self.failed {
+            let result = write!(self.out, "{}{}{}{}", color::Fg(color::Reset), color::Bg(color::Reset), clear::All, cursor::Show)
+                .and_then(|_| self.out.flush());
+            self.check(result);
+        }
+    }
+}
+
+// Shows the logical pixels, the way the panels look once they are arranged
+// as the pixel mapper expects.
+fn render_half_blocks(frame: &Frame) -> String {
+    let black = Pixel::new();
+    let mut screen = String::new();
+    for line in 0..frame.height.div_ceil(2) {
+        write!(screen, "{}", cursor::Goto(1, line as u16 + 1)).unwrap();
+        for col in 0..frame.width {
+            let top = frame.pixels[line * 2][col];
+            let bottom = frame.pixels.get(line * 2 + 1).map_or(black, |pixels| pixels[col]);
+            write!(screen, "{}{}{}", color::Fg(rgb(top)), color::Bg(rgb(bottom)), UPPER_HALF_BLOCK).unwrap();
+        }
+        write!(screen, "{}{}", color::Fg(color::Reset), color::Bg(color::Reset)).unwrap();
+    }
+    screen
+}
+
+fn rgb(pixel: Pixel) -> color::Rgb {
+    let channel = |value: u16| value.min(255) as u8;
+    color::Rgb(channel(pixel.r), channel(pixel.g), channel(pixel.b))
+}
+
+#[test]
+fn render_half_blocks_test() {
+    let mut frame = Frame::with_mapper(&PanelConfig::new(2, 4, 2).unwrap(), &IdentityMapper).unwrap();
+    frame.pixels[0][0] = Pixel::new_colored_pixel(255, 0, 0);
+    frame.pixels[1][0] = Pixel::new_colored_pixel(0, 0, 255);
+    frame.pixels[3][1] = Pixel::new_colored_pixel(300, 16, 1);
+
+    let screen = render_half_blocks(&frame);
+    assert!(screen.starts_with(&format!("{}\x1b[38;2;255;0;0m\x1b[48;2;0;0;255m\u{2580}", cursor::Goto(1, 1))));
+    assert!(screen.contains(&format!("{}", cursor::Goto(1, 2))));
+    assert!(screen.contains("\x1b[38;2;0;0;0m\x1b[48;2;255;16;1m\u{2580}"));
+    assert_eq!(4, screen.matches(UPPER_HALF_BLOCK).count());
+}
+
+#[test]
+fn terminal_matrix_draws_changed_frames_once_test() {
+    let config = PanelConfig::new(2, 4, 2).unwrap();
+    let mut frame = Frame::with_mapper(&config, &IdentityMapper).unwrap();
+    frame.pixels[0][1] = Pixel::new_colored_pixel(0, 255, 0);
+    let mut output = Vec::new();
+    let mut terminal = TerminalMatrix::new(&mut output).unwrap();
+
+    terminal.load_frame(&frame);
+    terminal.refresh(&frame);
+    terminal.refresh(&frame);
+    drop(terminal);
+
+    let output = String::from_utf8(output).unwrap();
+    assert_eq!(1, output.matches("\x1b[38;2;0;255;0m").count());
+}
+
+#[test]
+fn terminal_matrix_survives_closed_output_test() {
+    struct ClosedPipe;
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::from(io::ErrorKind::BrokenPipe))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    assert!(TerminalMatrix::new(ClosedPipe).is_err());
+    let mut terminal = TerminalMatrix { out: ClosedPipe, screen: String::new(), dirty: false, failed: false };
+    let frame = Frame::with_mapper(&PanelConfig::new(2, 4, 2).unwrap(), &IdentityMapper).unwrap();
+    terminal.load_frame(&frame);
+    terminal.refresh(&frame);
+    assert!(terminal.failed);
+    terminal.blank();
+}
@@ -1,84 +1,322 @@
 use std;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-use mmap::MemoryMap;
 use shuteye::sleep;
 
-use super::gpio_driver::mmap_bcm_register;
+use super::gpio_driver::{describe_open_error, open_register_file, DriverError, DEVMEM_PATH};
+use super::register_block::{MmapRegisterBlock, RegisterBlock};
+#[cfg(test)]
+use std::cell::{Cell, RefCell};
+#[cfg(test)]
+use super::register_block::FakeRegisterBlock;
 
 const TIMER_REGISTER_OFFSET: u64 = 0x3000;
-const TIMER_OVERFLOW_VALUE: u32 = 4294967295;
+// Lower 32 bits of the free-running microsecond counter of the system timer.
+const TIMER_CLO: usize = 1;
+// How much longer than asked the kernel typically lets a sleep last.
+const DEFAULT_JITTER_ALLOWANCE: u32 = 60 * 1000;
+// Shorter sleeps are not worth giving up the CPU for.
+const MIN_SLEEP_NANOS: u32 = 5000;
+// Waits shorter than this are over before the counter could be read.
+const MIN_WAIT_NANOS: u32 = 20;
+const CALIBRATION_SLEEP_NANOS: u32 = 100 * 1000;
 
+/// A free-running microsecond counter that wraps around at `u32::MAX`, and a
+/// way to give up the CPU for a while.
+pub trait Clock {
+    fn now_micros(&self) -> u32;
 
+    /// A finer reading for clocks that have one, e.g. to timestamp traces.
+    fn now_nanos(&self) -> u64 {
+        self.now_micros() as u64 * 1000
+    }
+
+    /// Sleeps for at least `nanos`, usually longer.
+    fn sleep(&self, nanos: u32);
+}
+
+impl<C: Clock + ?Sized> Clock for Box<C> {
+    fn now_micros(&self) -> u32 {
+        (**self).now_micros()
+    }
+
+    fn now_nanos(&self) -> u64 {
+        (**self).now_nanos()
+    }
+
+    fn sleep(&self, nanos: u32) {
+        (**self).sleep(nanos)
+    }
+}
+
+// Lets a test keep a handle on the clock the timer sleeps on.
+impl<C: Clock + ?Sized> Clock for Rc<C> {
+    fn now_micros(&self) -> u32 {
+        (**self).now_micros()
+    }
+
+    fn now_nanos(&self) -> u64 {
+        (**self).now_nanos()
+    }
+
+    fn sleep(&self, nanos: u32) {
+        (**self).sleep(nanos)
+    }
+}
+
+/// The system timer of the BCM chip.
+pub struct SystemTimerClock<R: RegisterBlock = MmapRegisterBlock> {
+    registers: R,
+}
+
+impl<R: RegisterBlock> SystemTimerClock<R> {
+    pub fn with_registers(registers: R) -> SystemTimerClock<R> {
+        SystemTimerClock {
+            registers,
+        }
+    }
+}
 
-pub struct Timer {
-    _timemap: Option<MemoryMap>,
-    timereg: *mut u32,
+impl<R: RegisterBlock> Clock for SystemTimerClock<R> {
+    fn now_micros(&self) -> u32 {
+        self.registers.read(TIMER_CLO)
+    }
+
+    fn sleep(&self, nanos: u32) {
+        sleep(Duration::new(0, nanos));
+    }
+}
+
+/// Stand-in for the system timer when /dev/mem is not accessible, counting
+/// microseconds of CLOCK_MONOTONIC.
+pub struct MonotonicClock {
+    start: Instant,
+}
+
+impl MonotonicClock {
+    pub fn new() -> MonotonicClock {
+        MonotonicClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now_micros(&self) -> u32 {
+        let elapsed = self.start.elapsed();
+        (elapsed.as_secs() * 1_000_000 + elapsed.subsec_micros() as u64) as u32
+    }
+
+    fn now_nanos(&self) -> u64 {
+        let elapsed = self.start.elapsed();
+        elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64
+    }
+
+    fn sleep(&self, nanos: u32) {
+        sleep(Duration::new(0, nanos));
+    }
+}
+
+/// Clock that only moves when it is read or slept on. Every read advances it
+/// by `tick_nanos`, every sleep by the requested time plus `oversleep_nanos`.
+#[cfg(test)]
+pub struct FakeClock {
+    now_nanos: Cell<u64>,
+    tick_nanos: u32,
+    oversleep_nanos: u32,
+    sleeps: RefCell<Vec<u32>>,
+}
+
+#[cfg(test)]
+impl FakeClock {
+    pub fn new(start_micros: u32) -> FakeClock {
+        FakeClock {
+            now_nanos: Cell::new(start_micros as u64 * 1000),
+            tick_nanos: 1000,
+            oversleep_nanos: 0,
+            sleeps: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn with_tick(mut self, tick_nanos: u32) -> FakeClock {
+        self.tick_nanos = tick_nanos;
+        self
+    }
+
+    pub fn with_oversleep(mut self, oversleep_nanos: u32) -> FakeClock {
+        self.oversleep_nanos = oversleep_nanos;
+        self
+    }
+
+    /// Nanoseconds since the counter was at zero, without wrapping.
+    pub fn now_nanos(&self) -> u64 {
+        self.now_nanos.get()
+    }
+
+    pub fn sleeps(&self) -> Vec<u32> {
+        self.sleeps.borrow().clone()
+    }
+
+    fn advance(&self, nanos: u64) {
+        self.now_nanos.set(self.now_nanos.get() + nanos);
+    }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn now_micros(&self) -> u32 {
+        let now = (self.now_nanos.get() / 1000) as u32;
+        self.advance(self.tick_nanos as u64);
+        now
+    }
+
+    // Unlike the microsecond counter, looking does not move the clock.
+    fn now_nanos(&self) -> u64 {
+        FakeClock::now_nanos(self)
+    }
+
+    fn sleep(&self, nanos: u32) {
+        self.sleeps.borrow_mut().push(nanos);
+        self.advance(nanos as u64 + self.oversleep_nanos as u64);
+    }
+}
+
+pub struct Timer<C: Clock = Box<dyn Clock + Send>> {
+    clock: C,
+    // Long waits sleep until this long before the deadline and busy-wait the
+    // rest.
+    jitter_allowance: u32,
 }
 
 impl Timer {
-    unsafe fn read(self: &Timer) -> u32 {
-        std::ptr::read_volatile(self.timereg)
+    pub fn new(peripheral_base: u64) -> Result<Timer, DriverError> {
+        if let Err(why) = open_register_file(DEVMEM_PATH) {
+            eprintln!("{}", describe_open_error(DEVMEM_PATH, &why));
+            eprintln!("Falling back to CLOCK_MONOTONIC for the system timer, bitplane timing will be less precise");
+            return Ok(Timer::with_clock(Box::new(MonotonicClock::new())));
+        }
+
+        let registers = MmapRegisterBlock::new(peripheral_base, TIMER_REGISTER_OFFSET as usize)?;
+        Ok(Timer::with_clock(Box::new(SystemTimerClock::with_registers(registers))))
     }
+}
 
-    pub fn new() -> Timer {
-        //TODO: Implement this yourself.
-        let map = mmap_bcm_register(TIMER_REGISTER_OFFSET as usize);
+impl<C: Clock> Timer<C> {
+    pub fn with_clock(clock: C) -> Timer<C> {
+        Timer {
+            clock,
+            jitter_allowance: DEFAULT_JITTER_ALLOWANCE,
+        }
+    }
 
-        let mut timer: Timer = Timer {
-            _timemap: None,
-            timereg: 0 as *mut u32,
-        };
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
 
-        match &map {
-            &Some(ref map) => {
-                unsafe {
-                    timer.timereg = map.data() as *mut u32;
-                    timer.timereg.offset(1);
-                }
-            }
-            &None => {}
-        };
-        timer
-    }
-
-    pub fn nanosleep(self: &Timer, mut nanos: u32) {
-        //TODO: Implement this yourself.
-        let k_jitter_allowance = 60 * 1000;
-
-        if nanos > k_jitter_allowance + 5000{
-            let before_microsecs = unsafe { self.read() };
-            let nanosec_passed: u64;
-
-            match sleep(std::time::Duration::new(0, nanos - k_jitter_allowance)) {
-                Some(_time) => {
-                    let after_microsec = unsafe { self.read() };
-                    if after_microsec > before_microsecs {
-                        nanosec_passed = (1000 * (after_microsec - before_microsecs)) as u64;
-                    } else {
-                        nanosec_passed = 1000 * (TIMER_OVERFLOW_VALUE - before_microsecs + after_microsec) as u64;
-                    }
-                    if nanosec_passed > nanos as u64 {
-                        return;
-                    } else {
-                        nanos -= nanosec_passed as u32;
-                    }
-                }
-                None => {}
+    /// Sets the jitter allowance to the worst oversleep seen in `samples`
+    /// short sleeps, and returns it.
+    pub fn calibrate_jitter(&mut self, samples: usize) -> u32 {
+        let mut worst = 0;
+        for _sample in 0..samples {
+            let before = self.clock.now_micros();
+            self.clock.sleep(CALIBRATION_SLEEP_NANOS);
+            let slept = elapsed_nanos(before, self.clock.now_micros());
+            worst = worst.max(slept.saturating_sub(CALIBRATION_SLEEP_NANOS as u64));
+        }
+        self.jitter_allowance = worst.min(u32::MAX as u64) as u32;
+        self.jitter_allowance
+    }
+
+    /// Sleeps while the deadline is further away than the jitter allowance,
+    /// then busy-waits until it has passed.
+    pub fn nanosleep(&self, mut nanos: u32) {
+        if nanos > self.jitter_allowance.saturating_add(MIN_SLEEP_NANOS) {
+            let before = self.clock.now_micros();
+            self.clock.sleep(nanos - self.jitter_allowance);
+            let slept = elapsed_nanos(before, self.clock.now_micros());
+            if slept >= nanos as u64 {
+                return;
             }
+            nanos -= slept as u32;
         }
 
-        if nanos < 20 {
+        if nanos < MIN_WAIT_NANOS {
             return;
         }
 
-        let start_time: u32 = unsafe { self.read() };
-        let mut current_time: u32 = start_time;
-
-        while start_time + nanos * 1000 <= current_time {
-            current_time = unsafe { self.read() };
-        }
-        return;
+        let start = self.clock.now_micros();
+        while elapsed_nanos(start, self.clock.now_micros()) < nanos as u64 {}
     }
 }
 
+// The counter wraps around about every 71 minutes.
+pub fn elapsed_nanos(before_micros: u32, after_micros: u32) -> u64 {
+    after_micros.wrapping_sub(before_micros) as u64 * 1000
+}
+
+#[test]
+fn system_timer_clock_reads_counter_test() {
+    let registers = FakeRegisterBlock::new();
+    registers.set_word(0, 0xF);
+    registers.set_word(TIMER_CLO, 1234);
+    assert_eq!(1234, SystemTimerClock::with_registers(registers).now_micros());
+}
+
+#[test]
+fn nanosleep_busy_waits_short_delays_test() {
+    let timer = Timer::with_clock(FakeClock::new(0));
+    timer.nanosleep(3000);
+    assert!(timer.clock().sleeps().is_empty());
+    assert!(timer.clock().now_nanos() >= 3000);
+    assert!(timer.clock().now_nanos() <= 5000);
+}
+
+#[test]
+fn nanosleep_sleeps_long_delays_test() {
+    let timer = Timer::with_clock(FakeClock::new(0).with_oversleep(10 * 1000));
+    timer.nanosleep(200 * 1000);
+    assert_eq!(vec![140 * 1000], timer.clock().sleeps());
+    // The 50us the sleep left are busy-waited.
+    assert!(timer.clock().now_nanos() >= 200 * 1000);
+    assert!(timer.clock().now_nanos() <= 203 * 1000);
+}
+
+#[test]
+fn nanosleep_returns_after_oversleeping_test() {
+    let timer = Timer::with_clock(FakeClock::new(0).with_oversleep(100 * 1000));
+    timer.nanosleep(200 * 1000);
+    assert_eq!(1, timer.clock().sleeps().len());
+    assert!(timer.clock().now_nanos() <= 242 * 1000);
+}
+
+#[test]
+fn nanosleep_handles_counter_overflow_test() {
+    let start = u32::MAX - 50;
+    let timer = Timer::with_clock(FakeClock::new(start));
+    timer.nanosleep(200 * 1000);
+    let waited = timer.clock().now_nanos() - start as u64 * 1000;
+    assert!((200_000..=203_000).contains(&waited), "Waited {}ns", waited);
 
+    assert_eq!(3000, elapsed_nanos(u32::MAX - 1, 1));
+}
+
+#[test]
+fn calibrate_jitter_test() {
+    let mut timer = Timer::with_clock(FakeClock::new(0).with_oversleep(25 * 1000));
+    timer.nanosleep(200 * 1000);
+    assert_eq!(Some(&(200 * 1000 - DEFAULT_JITTER_ALLOWANCE)), timer.clock().sleeps().last());
+    // Reading the counter after the sleep adds a tick.
+    assert_eq!(26 * 1000, timer.calibrate_jitter(10));
+    assert_eq!(11, timer.clock().sleeps().len());
+    timer.nanosleep(200 * 1000);
+    assert_eq!(Some(&(174 * 1000)), timer.clock().sleeps().last());
+}
+
+#[test]
+fn monotonic_clock_nanosleep_test() {
+    let timer = Timer::with_clock(MonotonicClock::new());
+    let start = Instant::now();
+    timer.nanosleep(2 * 1000 * 1000);
+    assert!(start.elapsed() >= Duration::from_millis(2));
+}